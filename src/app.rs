@@ -1,80 +1,304 @@
 mod chat;
-mod welcome;
+mod codec;
+mod commands;
+pub mod config;
+mod connection;
+mod format;
+mod group;
+mod history;
+mod members;
+mod message;
+mod profiles;
+mod protocol;
+mod search;
 pub mod security;
+mod session;
+mod sidebar;
+mod timestamp;
+mod voice;
+mod welcome;
+
+use std::collections::BTreeMap;
 
-use std::net::TcpStream;
+use iced::{widget::row, window, Element, Subscription, Task, Theme};
 
-use iced::{Element, Subscription};
+use chat::{ChatViewState, RoomRef};
+use config::Config;
+use profiles::Account;
+
+// messages draw their formatting with its colors too
+pub const THEME: Theme = Theme::KanagawaLotus;
 
 enum Screen {
-    WelcomeScreen(welcome::WelcomeViewState),
-    ChatScreen(chat::ChatViewState),
+    Profiles(profiles::ProfilesViewState),
+    Welcome(welcome::WelcomeViewState),
+    Chat(RoomRef),
 }
 
 pub struct AppState {
     screen: Screen,
-    tcp_stream: TcpStream,
+    config: Config,
+    // every joined room keeps its own session, messages and draft
+    rooms: BTreeMap<RoomRef, ChatViewState>,
 }
 
 impl AppState {
-    pub fn new(tcp_stream: TcpStream) -> Self {
-        AppState {
-            screen: Screen::WelcomeScreen(welcome::WelcomeViewState::new(
-                tcp_stream.try_clone().unwrap(),
-            )),
-            tcp_stream,
+    pub fn new(config: Config) -> (Self, Task<AppMessage>) {
+        (
+            AppState {
+                screen: Screen::Profiles(profiles::ProfilesViewState::new(&config)),
+                config,
+                rooms: BTreeMap::new(),
+            },
+            Task::none(),
+        )
+    }
+
+    fn select_room(&mut self, room: RoomRef) {
+        for (room_ref, chat_view_state) in self.rooms.iter_mut() {
+            chat_view_state.set_active(*room_ref == room);
+        }
+        self.screen = Screen::Chat(room);
+    }
+
+    fn rename_room(&mut self, room: &RoomRef, renamed: RoomRef) {
+        if let Some(chat_view_state) = self.rooms.remove(room) {
+            self.rooms.insert(renamed.clone(), chat_view_state);
+        }
+        if matches!(&self.screen, Screen::Chat(r) if r == room) {
+            self.screen = Screen::Chat(renamed);
         }
     }
+
+    fn show_profiles(&mut self) {
+        for chat_view_state in self.rooms.values_mut() {
+            chat_view_state.set_active(false);
+        }
+        self.screen = Screen::Profiles(profiles::ProfilesViewState::new(&self.config));
+    }
+
+    fn show_welcome(&mut self, account: Account) -> Task<AppMessage> {
+        for chat_view_state in self.rooms.values_mut() {
+            chat_view_state.set_active(false);
+        }
+        let open_rooms = self.rooms.keys().cloned().collect();
+        let (welcome_view_state, task) = welcome::WelcomeViewState::new(account, open_rooms);
+        self.screen = Screen::Welcome(welcome_view_state);
+        task.map(AppMessage::WelcomeMessages)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum AppMessage {
+    ProfilesMessages(profiles::ProfilesViewMessage),
     WelcomeMessages(welcome::WelcomeViewMessage),
-    ChatMessages(chat::ChatViewMessage),
+    ChatMessages(RoomRef, chat::ChatViewMessage),
+    SidebarMessages(sidebar::SidebarMessage),
+    WindowCloseRequested(window::Id),
 }
 
-pub fn update(app_state: &mut AppState, message: AppMessage) {
+pub fn update(app_state: &mut AppState, message: AppMessage) -> Task<AppMessage> {
     match message {
+        AppMessage::ProfilesMessages(profiles_view_message) => {
+            if let Screen::Profiles(profiles_view_state) = &mut app_state.screen {
+                let action = profiles::update(
+                    profiles_view_state,
+                    &mut app_state.config,
+                    profiles_view_message,
+                );
+                match action {
+                    profiles::ProfilesViewAction::Open(account) => {
+                        return app_state.show_welcome(account);
+                    }
+                    profiles::ProfilesViewAction::Run(task) => {
+                        return task.map(AppMessage::ProfilesMessages);
+                    }
+                    profiles::ProfilesViewAction::None => {}
+                }
+            }
+        }
         AppMessage::WelcomeMessages(welcome_view_message) => {
-            if let Screen::WelcomeScreen(welcome_view_state) = &mut app_state.screen {
+            if let Screen::Welcome(welcome_view_state) = &mut app_state.screen {
                 let action = welcome::welcome_view_update(welcome_view_state, welcome_view_message);
                 match action {
-                    welcome::WelcomeViewAction::RoomJoined(success_message, username, room_id) => {
-                        app_state.screen = Screen::ChatScreen(chat::ChatViewState::new(
+                    welcome::WelcomeViewAction::RoomJoined(
+                        success_message,
+                        account,
+                        room_id,
+                        room_key,
+                        connection,
+                    ) => {
+                        let chat_view_state = ChatViewState::new(
                             vec![success_message],
-                            username,
+                            *account,
                             room_id,
-                            app_state.tcp_stream.try_clone().unwrap(),
-                        ));
+                            room_key,
+                            connection,
+                            app_state.config.send_typing,
+                            app_state.config.audio.clone(),
+                        );
+                        // the welcome form never joins a room that is already open
+                        let room = chat_view_state.room_ref();
+                        app_state.rooms.insert(room.clone(), chat_view_state);
+                        app_state.select_room(room);
+                    }
+                    welcome::WelcomeViewAction::SelectRoom(room) => {
+                        if let Some(chat_view_state) = app_state.rooms.get_mut(&room) {
+                            chat_view_state.show_direct(None);
+                            app_state.select_room(room);
+                        }
+                    }
+                    welcome::WelcomeViewAction::Run(task) => {
+                        return task.map(AppMessage::WelcomeMessages);
                     }
+                    welcome::WelcomeViewAction::ChangeProfile => app_state.show_profiles(),
                     welcome::WelcomeViewAction::None => {}
                 }
             }
         }
-        AppMessage::ChatMessages(chat_view_message) => {
-            if let Screen::ChatScreen(chat_view_state) = &mut app_state.screen {
+        AppMessage::ChatMessages(room, chat_view_message) => {
+            // frames keep arriving for rooms that are not on screen
+            if let Some(chat_view_state) = app_state.rooms.get_mut(&room) {
                 let action = chat::update(chat_view_state, chat_view_message);
+                // a new name takes the room to a new key
+                let renamed = chat_view_state.room_ref();
+                let room = if renamed != room {
+                    app_state.rename_room(&room, renamed.clone());
+                    renamed
+                } else {
+                    room
+                };
                 match action {
                     chat::ChatViewAction::None => {}
-                    chat::ChatViewAction::Disconnect => {
-                        // restart app
+                    chat::ChatViewAction::Run(task) => {
+                        return task
+                            .map(move |message| AppMessage::ChatMessages(room.clone(), message));
+                    }
+                    chat::ChatViewAction::JoinRoom {
+                        mut account,
+                        room_id,
+                    } => {
+                        let joined = RoomRef {
+                            server: account.server_address.to_string(),
+                            room_id: room_id.clone(),
+                            name: account.name.clone(),
+                        };
+                        if app_state.rooms.contains_key(&joined) {
+                            app_state.select_room(joined);
+                        } else {
+                            // the passphrase is asked for on the welcome form, which
+                            // starts out on the first room
+                            account.rooms.insert(0, room_id);
+                            return app_state.show_welcome(account);
+                        }
+                    }
+                    chat::ChatViewAction::Disconnect(account) => {
+                        app_state.rooms.remove(&room);
+                        let on_screen = matches!(&app_state.screen, Screen::Chat(r) if *r == room);
+                        if on_screen {
+                            match app_state.rooms.keys().next().cloned() {
+                                Some(next) => app_state.select_room(next),
+                                None => return app_state.show_welcome(account),
+                            }
+                        }
                     }
                 }
             }
         }
+        AppMessage::SidebarMessages(sidebar_message) => match sidebar_message {
+            sidebar::SidebarMessage::SelectRoom(room) => {
+                if let Some(chat_view_state) = app_state.rooms.get_mut(&room) {
+                    chat_view_state.show_direct(None);
+                    app_state.select_room(room);
+                }
+            }
+            sidebar::SidebarMessage::SelectDirect(room, peer) => {
+                if let Some(chat_view_state) = app_state.rooms.get_mut(&room) {
+                    chat_view_state.show_direct(Some(peer));
+                    app_state.select_room(room);
+                }
+            }
+            sidebar::SidebarMessage::JoinAnotherRoom => {
+                if let Screen::Chat(room) = &app_state.screen {
+                    // start from the server and name of the room on screen
+                    if let Some(chat_view_state) = app_state.rooms.get(room) {
+                        let account = chat_view_state.account();
+                        return app_state.show_welcome(account);
+                    }
+                }
+            }
+        },
+        AppMessage::WindowCloseRequested(_id) => {
+            // let the servers know we left before the process goes away
+            let closing = app_state
+                .rooms
+                .values_mut()
+                .filter_map(|chat_view_state| chat_view_state.leave())
+                .map(|closed| Task::future(closed).discard())
+                .collect::<Vec<Task<AppMessage>>>();
+            return Task::batch(closing).chain(iced::exit());
+        }
     }
+    Task::none()
 }
 
-pub fn view(app_state: &AppState) -> Element<AppMessage> {
-    match &app_state.screen {
-        Screen::ChatScreen(m) => chat::view(m).map(AppMessage::ChatMessages),
-        Screen::WelcomeScreen(m) => welcome::welcome_view(m).map(AppMessage::WelcomeMessages),
+pub fn view(app_state: &AppState) -> Element<'_, AppMessage> {
+    let content = match &app_state.screen {
+        Screen::Chat(room) => match app_state.rooms.get(room) {
+            Some(m) => {
+                chat::view(m).map(move |message| AppMessage::ChatMessages(room.clone(), message))
+            }
+            None => row![].into(),
+        },
+        Screen::Welcome(m) => welcome::welcome_view(m).map(AppMessage::WelcomeMessages),
+        Screen::Profiles(m) => {
+            profiles::view(m, &app_state.config).map(AppMessage::ProfilesMessages)
+        }
+    };
+    if app_state.rooms.is_empty() {
+        return content;
     }
+
+    let on_screen = |room: &RoomRef| matches!(&app_state.screen, Screen::Chat(r) if r == room);
+    let entries = app_state
+        .rooms
+        .iter()
+        .map(|(room, chat_view_state)| sidebar::SidebarEntry {
+            room: room.clone(),
+            unread: chat_view_state.unread(),
+            selected: on_screen(room) && chat_view_state.direct_view().is_none(),
+        })
+        .collect();
+    let direct_entries = app_state
+        .rooms
+        .iter()
+        .flat_map(|(room, chat_view_state)| {
+            chat_view_state
+                .direct_threads()
+                .map(move |(peer, unread)| sidebar::DirectEntry {
+                    room: room.clone(),
+                    peer: peer.to_string(),
+                    unread,
+                    selected: on_screen(room) && chat_view_state.direct_view() == Some(peer),
+                })
+        })
+        .collect();
+    row![
+        sidebar::view(entries, direct_entries).map(AppMessage::SidebarMessages),
+        content
+    ]
+    .into()
 }
 
 pub fn subscription(app_state: &AppState) -> Subscription<AppMessage> {
-    if let Screen::ChatScreen(_m) = &app_state.screen {
-        return Subscription::run(chat::recv_updates).map(AppMessage::ChatMessages);
-    }
-    Subscription::none()
+    // every joined room keeps receiving, not only the one on screen
+    let rooms = app_state.rooms.iter().map(|(room, chat_view_state)| {
+        let room = room.clone();
+        chat::subscription(chat_view_state)
+            .with(room)
+            .map(|(room, message)| AppMessage::ChatMessages(room, message))
+    });
+    Subscription::batch(
+        rooms.chain([window::close_requests().map(AppMessage::WindowCloseRequested)]),
+    )
 }
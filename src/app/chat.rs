@@ -1,210 +1,1828 @@
-use core::str;
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::TcpStream,
-    sync::Arc,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use base64::Engine;
 use iced::{
-    advanced::graphics::core::font,
-    futures::{stream::FusedStream, SinkExt, Stream, StreamExt},
+    advanced::{
+        graphics::core::font,
+        widget::{self, operation, Operation},
+    },
+    futures::{channel::mpsc::Sender, SinkExt, Stream, StreamExt},
+    keyboard::{key, Key},
     stream,
-    widget::{button, column, container, row, scrollable, text, text_input, Column, Row},
-    Alignment, Color, Element, Font, Length, Padding,
+    widget::{
+        button, column, container, markdown, row, scrollable, scrollable::AbsoluteOffset, text,
+        text_input, tooltip, Column, Row,
+    },
+    Alignment, Color, Element, Font, Length, Padding, Rectangle, Subscription, Task, Vector,
 };
 use std::sync::Mutex;
 
-use super::security::{decrypt, encrypt};
+use super::commands::{self, Command, Input, COMMANDS};
+use super::config::ServerAddress;
+use super::connection::Connection;
+use super::format::{self, Block};
+use super::group::GroupSession;
+use super::history::{HistoryStore, RoomHistory};
+use super::members::Roster;
+use super::message::{self, ConversationMessage, DeliveryState, MessageKind, Reactions, Revision};
+use super::profiles::Account;
+use super::protocol::{
+    ChatPayload, ClientFrame, MessageBody, Presence, PresenceNotice, ServerFrame, TypingNotice,
+};
+use super::search::{self, SearchHit, SearchIndex, SearchQuery};
+use super::security::{decrypt, RoomKey};
+use super::session::{self, ConnectionStatus, SessionEvent, SessionHandle};
+use super::timestamp;
+use super::voice::{AudioBackend, VoiceError, VoiceSession};
+
+// tells chat sessions apart in the subscription key, so rejoining the same room
+// starts a fresh subscription instead of reusing the old one
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+const GROUP_WINDOW_SECS: u64 = 5 * 60;
+// how long one of our messages may wait for the server while we are connected
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// we repeat "started" this often while typing, so members who miss one still see it
+const TYPING_RESEND: Duration = Duration::from_secs(3);
+// a pause this long in our typing counts as having stopped
+const TYPING_IDLE: Duration = Duration::from_secs(5);
+// a member's "started" is forgotten this long after it arrived unless repeated
+const TYPING_EXPIRY: Duration = Duration::from_secs(8);
+// we count as idle after this long without typing or sending
+const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+const PRESENCE_CHECK: Duration = Duration::from_secs(30);
+// what the reaction picker offers
+const REACTION_PICKER: &[&str] = &["👍", "❤️", "😂", "😮", "😢", "🎉", "👀"];
+
+// the same room id can exist on two servers, and one server can be used under two
+// names, so rooms are keyed by all three
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomRef {
+    pub server: String,
+    pub room_id: String,
+    pub name: String,
+}
 
 pub struct ChatViewState {
+    session_id: u64,
     name: String,
     room_id: String,
+    room_key: RoomKey,
     messages: Arc<Mutex<Vec<ConversationMessage>>>,
     current_message: String,
-    tcp_stream: TcpStream,
+    server_address: ServerAddress,
+    // handed to the session task once the subscription starts
+    connection: Option<Connection>,
+    session: Option<SessionHandle>,
+    events: Option<Sender<SessionEvent>>,
+    connection_status: ConnectionStatus,
+    // ids of our messages waiting for a connection, oldest first
+    outbox: Vec<String>,
+    // whether this room is the one on screen; messages arriving otherwise count as unread
+    active: bool,
+    unread: usize,
+    // whether members see when we are typing
+    send_typing: bool,
+    // when we last told the room we are typing, None while we are not
+    typing_sent_at: Option<Instant>,
+    last_input_at: Option<Instant>,
+    // members typing right now, and when their last notice runs out
+    typing: BTreeMap<String, Instant>,
+    roster: Roster,
+    show_members: bool,
+    // what we last told the room about ourselves
+    presence: Presence,
+    voice: VoiceState,
+    // kept across joins, and applied to the session once it runs
+    muted: bool,
+    deafened: bool,
+    audio: AudioBackend,
+    last_active_at: Instant,
+    group_session: GroupSession,
+    history: Option<HistoryStore>,
+    room_history: Option<RoomHistory>,
+    search_index: SearchIndex,
+    // open while the search panel is shown
+    search_panel: Option<SearchPanel>,
+    // conversations with single members, by their name; kept in memory only
+    direct_threads: BTreeMap<String, DirectThread>,
+    direct_view: Option<String>,
+    // id of the message the next one we send answers
+    replying_to: Option<String>,
+    // id of our message whose text is in the input, to be sent as an edit
+    editing: Option<String>,
+    // root of the thread shown next to the conversation
+    thread_view: Option<String>,
+    // by message id, for the room and direct threads alike; kept in memory only
+    reactions: HashMap<String, Reactions>,
+    // id of the message whose reaction picker is open
+    reaction_picker: Option<String>,
+    // parsed markup of chat messages that have any, by message id
+    formatted: HashMap<String, Vec<Block>>,
+    // messages are shown as typed instead of formatted
+    show_raw: bool,
+    messages_scroll_id: scrollable::Id,
+    message_input_id: text_input::Id,
     conversation_message_manager: ConversationMessageManager,
 }
 
+#[derive(Default)]
+struct DirectThread {
+    messages: Vec<ConversationMessage>,
+    unread: usize,
+}
+
+enum VoiceState {
+    Off,
+    // asked the server to join, waiting for its offer and then for the session to start
+    Joining,
+    Active(VoiceSession),
+}
+
+#[derive(Default)]
+struct SearchPanel {
+    text: String,
+    sender: String,
+    from: String,
+    to: String,
+    hits: Vec<SearchHit>,
+    // position of the message scrolled to
+    selected: Option<usize>,
+    error: Option<String>,
+}
+
+impl SearchPanel {
+    fn run(&mut self, search_index: &SearchIndex) {
+        self.hits.clear();
+        self.error = None;
+        let parse = |s: &str| match s.trim() {
+            "" => Ok(None),
+            s => timestamp::parse_date(s).map(Some).ok_or(()),
+        };
+        let (Ok(from), Ok(to)) = (parse(&self.from), parse(&self.to)) else {
+            self.error = Some(String::from("Dates are written like 2024-03-01"));
+            return;
+        };
+        let query = SearchQuery {
+            text: self.text.clone(),
+            sender: self.sender.clone(),
+            from,
+            to: to.map(timestamp::end_of_day),
+        };
+        self.hits = search_index.search(&query);
+    }
+}
+
 impl ChatViewState {
     pub fn new(
         mut messages: Vec<String>,
-        name: String,
+        account: Account,
         room_id: String,
-        tcp_stream: TcpStream,
+        room_key: RoomKey,
+        connection: Connection,
+        send_typing: bool,
+        audio: AudioBackend,
     ) -> Self {
-        messages.push(format!("ROOM_JOIN_SUCCESS_MESSAGE You have joined as {}", name).to_string());
+        let Account {
+            server_address,
+            name,
+            identity,
+            history,
+            ..
+        } = account;
         let mut cmm = ConversationMessageManager::new();
-        let messages = cmm.cms_from_vec(messages);
+
+        let mut earlier = vec![];
+        let mut notices = vec![];
+        let mut search_index = SearchIndex::new();
+        let mut formatted = HashMap::new();
+        let server = server_address.to_string();
+        let room_history = match &history {
+            Some(history) => match history.open_room(&server, &room_id, &name) {
+                Ok((room_history, saved)) => {
+                    for cm in saved {
+                        if cm.kind == MessageKind::Chat && !cm.is_deleted() {
+                            cmm.assign_color(&color_key(&cm));
+                            search_index.add(
+                                earlier.len(),
+                                cm.time(),
+                                &cm.sender_name,
+                                &cm.content,
+                            );
+                            if let Some(blocks) = format::parse(&cm.content) {
+                                formatted.insert(cm.id.clone(), blocks);
+                            }
+                        }
+                        earlier.push(cm);
+                    }
+                    if !earlier.is_empty() {
+                        notices.push(ConversationMessage::notice(
+                            MessageKind::System,
+                            String::from("Messages above were saved on this device"),
+                        ));
+                    }
+                    Some(room_history)
+                }
+                Err(e) => {
+                    notices.push(ConversationMessage::notice(
+                        MessageKind::Error,
+                        format!("Could not load history: {}", e),
+                    ));
+                    None
+                }
+            },
+            None => None,
+        };
+        messages.push(format!("You have joined as {}", name));
+        let joined = messages
+            .into_iter()
+            .map(|content| ConversationMessage::notice(MessageKind::Join, content));
+        earlier.extend(notices.into_iter().chain(joined));
+        let mut roster = Roster::new();
+        roster.joined(&name);
+
         ChatViewState {
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            send_typing,
+            typing_sent_at: None,
+            last_input_at: None,
+            typing: BTreeMap::new(),
+            roster,
+            show_members: false,
+            presence: Presence::Online,
+            voice: VoiceState::Off,
+            muted: false,
+            deafened: false,
+            audio,
+            last_active_at: Instant::now(),
+            group_session: GroupSession::new(name.clone(), room_key.clone(), identity),
             name,
             room_id,
-            messages: Arc::new(Mutex::new(messages)),
+            room_key,
+            messages: Arc::new(Mutex::new(earlier)),
             current_message: String::new(),
-            tcp_stream,
+            server_address,
+            connection: Some(connection),
+            session: None,
+            events: None,
+            connection_status: ConnectionStatus::Connected,
+            outbox: vec![],
+            active: true,
+            unread: 0,
+            history,
+            room_history,
+            search_index,
+            search_panel: None,
+            direct_threads: BTreeMap::new(),
+            direct_view: None,
+            replying_to: None,
+            editing: None,
+            thread_view: None,
+            reactions: HashMap::new(),
+            reaction_picker: None,
+            formatted,
+            show_raw: false,
+            messages_scroll_id: scrollable::Id::unique(),
+            message_input_id: text_input::Id::unique(),
             conversation_message_manager: cmm,
         }
     }
+
+    pub fn room_ref(&self) -> RoomRef {
+        RoomRef {
+            server: self.server_address.to_string(),
+            room_id: self.room_id.clone(),
+            name: self.name.clone(),
+        }
+    }
+
+    // the server and identity this room was joined with, for joining more rooms
+    pub fn account(&self) -> Account {
+        Account {
+            server_address: self.server_address.clone(),
+            name: self.name.clone(),
+            rooms: vec![],
+            identity: self.group_session.identity().clone(),
+            history: self.history.clone(),
+        }
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        if active {
+            self.mark_read();
+        }
+    }
+
+    // whatever is on screen has been seen
+    fn mark_read(&mut self) {
+        match &self.direct_view {
+            Some(peer) => self.direct_threads.entry(peer.clone()).or_default().unread = 0,
+            None => self.unread = 0,
+        }
+    }
+
+    fn join_frame(&self) -> ClientFrame {
+        ClientFrame::JoinRoom {
+            room_id: self.room_id.clone(),
+            name: self.name.clone(),
+        }
+    }
+
+    // spawns the task owning the connection; with no connection it starts by reconnecting,
+    // and the announcement waits for the Connected status that follows
+    fn start_session(&mut self) {
+        let Some(events) = self.events.clone() else {
+            return;
+        };
+        let connection = self.connection.take();
+        let joined = connection.is_some();
+        self.session = Some(SessionHandle::spawn(
+            connection,
+            self.server_address.clone(),
+            self.join_frame(),
+            events,
+        ));
+        if joined {
+            self.announce();
+        }
+    }
+
+    // run on every (re)join: our identity key lets members start the sender key
+    // exchange, and the member list catches up on who came and went meanwhile
+    fn announce(&mut self) {
+        let frame = self.group_session.publish();
+        self.send_frame(frame);
+        // members we already know get our sender key before anything queued meanwhile
+        for frame in self.group_session.sender_key_deliveries() {
+            self.send_frame(frame);
+        }
+        let frame = ClientFrame::ListMembers {
+            room_id: self.room_id.clone(),
+        };
+        self.send_frame(frame);
+        self.presence = self.current_presence();
+        self.send_presence();
+    }
+
+    fn current_presence(&self) -> Presence {
+        if matches!(self.voice, VoiceState::Active(_)) {
+            Presence::InVoice
+        } else if self.last_active_at.elapsed() >= IDLE_AFTER {
+            Presence::Idle
+        } else {
+            Presence::Online
+        }
+    }
+
+    fn refresh_presence(&mut self) {
+        let presence = self.current_presence();
+        if presence != self.presence {
+            self.presence = presence;
+            self.send_presence();
+        }
+    }
+
+    fn send_presence(&mut self) {
+        self.roster.set_presence(&self.name, self.presence);
+        if let Some(session) = &self.session {
+            session.send(ClientFrame::Presence(PresenceNotice {
+                name: self.name.clone(),
+                presence: self.presence,
+            }));
+        }
+    }
+
+    // the key colors are assigned under, matching `color_key` for their messages
+    fn member_key(&self, name: &str) -> String {
+        if name == self.name {
+            return self.group_session.fingerprint();
+        }
+        self.group_session
+            .peer_fingerprint(name)
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    // shows the message; chat messages become searchable and, like joins, leaves and
+    // voice notices, are kept in the local history. our own messages are only kept
+    // once the server has them, see `mark_delivered`
+    fn push_message(&mut self, cm: ConversationMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        if cm.kind == MessageKind::Chat {
+            self.conversation_message_manager
+                .assign_color(&color_key(&cm));
+            self.search_index
+                .add(messages.len(), cm.time(), &cm.sender_name, &cm.content);
+            if let Some(blocks) = format::parse(&cm.content) {
+                self.formatted.insert(cm.id.clone(), blocks);
+            }
+        }
+        let kept = matches!(
+            cm.kind,
+            MessageKind::Chat | MessageKind::Join | MessageKind::Leave | MessageKind::Voice
+        ) && cm.delivery != DeliveryState::Pending;
+        if let Some(room_history) = self.room_history.as_ref().filter(|_| kept) {
+            if let Err(e) = room_history.append(&cm) {
+                println!("Could not save message to history: {}", e);
+            }
+        }
+        messages.push(cm);
+        drop(messages);
+        // keep open results in step with the conversation
+        if let Some(search_panel) = &mut self.search_panel {
+            search_panel.run(&self.search_index);
+        }
+    }
+
+    fn has_message(&self, id: &str) -> bool {
+        self.messages.lock().unwrap().iter().any(|cm| cm.id == id)
+    }
+
+    // runs `f` on one of our messages, in the room or in a direct thread; `f` also
+    // gets the member a direct message went to
+    fn with_own_message<R>(
+        &mut self,
+        id: &str,
+        f: impl FnOnce(&mut ConversationMessage, Option<&str>) -> R,
+    ) -> Option<R> {
+        {
+            let mut messages = self.messages.lock().unwrap();
+            if let Some(cm) = messages.iter_mut().find(|cm| cm.id == id && cm.is_own()) {
+                return Some(f(cm, None));
+            }
+        }
+        self.direct_threads
+            .iter_mut()
+            .find_map(|(peer, thread)| {
+                thread
+                    .messages
+                    .iter_mut()
+                    .find(|cm| cm.id == id && cm.is_own())
+                    .map(|cm| (cm, peer.as_str()))
+            })
+            .map(|(cm, peer)| f(cm, Some(peer)))
+    }
+
+    fn set_delivery(&mut self, id: &str, delivery: DeliveryState) {
+        self.with_own_message(id, |cm, _| cm.delivery = delivery);
+    }
+
+    fn delivery_of(&mut self, id: &str) -> Option<DeliveryState> {
+        self.with_own_message(id, |cm, _| cm.delivery)
+    }
+
+    // hands one of our messages to the session, or queues it until we are connected;
+    // every attempt carries the same id, so members never show it twice
+    fn send_chat(&mut self, id: &str) -> Task<ChatViewMessage> {
+        let Some((chat_payload, to)) = self.with_own_message(id, |cm, to| {
+            cm.delivery = DeliveryState::Pending;
+            let chat_payload = ChatPayload {
+                id: cm.id.clone(),
+                sent_at: cm.sent_at,
+                reply_to: cm.reply_to.clone(),
+                text: cm.content.clone(),
+            };
+            (chat_payload, to.map(str::to_string))
+        }) else {
+            return Task::none();
+        };
+        let frame = match &to {
+            None => self.group_session.encrypt(&chat_payload.serialize()),
+            Some(to) => match self
+                .group_session
+                .encrypt_direct(to, &chat_payload.serialize())
+            {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.set_delivery(id, DeliveryState::Failed);
+                    self.notify(MessageKind::Error, format!("Could not send: {}", e));
+                    return Task::none();
+                }
+            },
+        };
+        let handed_over = self.connection_status == ConnectionStatus::Connected
+            && match &self.session {
+                Some(session) => session.send_message(frame, chat_payload.id),
+                None => false,
+            };
+        if !handed_over {
+            if !self.outbox.iter().any(|queued| queued == id) {
+                self.outbox.push(id.to_string());
+            }
+            return Task::none();
+        }
+        delivery_timer(id.to_string())
+    }
+
+    fn flush_outbox(&mut self) -> Task<ChatViewMessage> {
+        let queued = std::mem::take(&mut self.outbox);
+        Task::batch(
+            queued
+                .iter()
+                .map(|id| self.send_chat(id))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn mark_delivered(&mut self, id: &str) {
+        self.outbox.retain(|queued| queued != id);
+        // direct threads only live as long as the session
+        let delivered = self.with_own_message(id, |cm, to| {
+            if cm.delivery == DeliveryState::Sent {
+                return None;
+            }
+            cm.delivery = DeliveryState::Sent;
+            to.is_none().then(|| cm.clone())
+        });
+        if let (Some(Some(cm)), Some(room_history)) = (delivered, &self.room_history) {
+            if let Err(e) = room_history.append(&cm) {
+                println!("Could not save message to history: {}", e);
+            }
+        }
+    }
+
+    // ids of our messages, in the room and in direct threads, still waiting
+    fn pending_ids(&self) -> Vec<String> {
+        let messages = self.messages.lock().unwrap();
+        messages
+            .iter()
+            .chain(
+                self.direct_threads
+                    .values()
+                    .flat_map(|thread| thread.messages.iter()),
+            )
+            .filter(|cm| cm.is_own() && cm.delivery == DeliveryState::Pending)
+            .map(|cm| cm.id.clone())
+            .collect()
+    }
+
+    // shows a notice wherever we are looking, the room or a direct thread
+    fn notify(&mut self, kind: MessageKind, content: String) {
+        let cm = ConversationMessage::notice(kind, content);
+        match self.direct_view.clone() {
+            Some(peer) => self
+                .direct_threads
+                .entry(peer)
+                .or_default()
+                .messages
+                .push(cm),
+            None => self.push_message(cm),
+        }
+    }
+
+    pub fn direct_threads(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.direct_threads
+            .iter()
+            .map(|(peer, thread)| (peer.as_str(), thread.unread))
+    }
+
+    // the member whose direct thread is on screen, None for the room itself
+    pub fn direct_view(&self) -> Option<&str> {
+        self.direct_view.as_deref()
+    }
+
+    pub fn show_direct(&mut self, peer: Option<String>) {
+        // typing notices go to the whole room, so they stop here
+        if peer.is_some() {
+            self.stop_typing();
+        }
+        // replies, edits and threads belong to the conversation they were started in
+        self.cancel_compose();
+        self.thread_view = None;
+        self.reaction_picker = None;
+        self.direct_view = peer;
+        self.mark_read();
+    }
+
+    // the room, or the direct thread on screen
+    fn with_visible_messages<R>(&self, f: impl FnOnce(&[ConversationMessage]) -> R) -> R {
+        match &self.direct_view {
+            Some(peer) => f(self
+                .direct_threads
+                .get(peer)
+                .map(|thread| thread.messages.as_slice())
+                .unwrap_or_default()),
+            None => f(&self.messages.lock().unwrap()),
+        }
+    }
+
+    // drops a reply or an edit in progress, with the text of the edit
+    fn cancel_compose(&mut self) {
+        self.replying_to = None;
+        if self.editing.take().is_some() {
+            self.current_message.clear();
+        }
+    }
+
+    // search positions follow the room's messages, so a message that changed means
+    // indexing them all again
+    fn reindex(&mut self) {
+        let messages = self.messages.lock().unwrap();
+        self.search_index = SearchIndex::new();
+        for (position, cm) in messages.iter().enumerate() {
+            if cm.kind == MessageKind::Chat && !cm.is_deleted() {
+                self.search_index
+                    .add(position, cm.time(), &cm.sender_name, &cm.content);
+            }
+        }
+        drop(messages);
+        if let Some(search_panel) = &mut self.search_panel {
+            search_panel.run(&self.search_index);
+        }
+    }
+
+    // written again under the same id, which replaces the earlier record the next
+    // time the history is loaded
+    fn save_revision(&self, cm: &ConversationMessage) {
+        if let Some(room_history) = &self.room_history {
+            if let Err(e) = room_history.append(cm) {
+                println!("Could not save message to history: {}", e);
+            }
+        }
+    }
+
+    // after a message's content changed
+    fn reformat(&mut self, id: &str, content: &str) {
+        match format::parse(content) {
+            Some(blocks) => self.formatted.insert(id.to_string(), blocks),
+            None => self.formatted.remove(id),
+        };
+    }
+
+    // edits or deletes one of our messages, here and for everyone who got it; only
+    // while connected, so members never miss the change
+    fn revise_own(&mut self, id: &str, revision: Revision, text: String) -> bool {
+        if self.connection_status != ConnectionStatus::Connected {
+            self.notify(
+                MessageKind::Error,
+                String::from("Not connected, the message was not changed"),
+            );
+            return false;
+        }
+        let to = self.with_own_message(id, |cm, to| {
+            (cm.delivery == DeliveryState::Sent && !cm.is_deleted()).then(|| to.map(str::to_string))
+        });
+        let Some(Some(to)) = to else {
+            return false;
+        };
+        let body = match revision {
+            Revision::Deleted => MessageBody::Delete { id: id.to_string() },
+            Revision::Original | Revision::Edited => MessageBody::Edit {
+                id: id.to_string(),
+                text: text.clone(),
+            },
+        };
+        let frame = match &to {
+            None => self.group_session.encrypt(&body.serialize()),
+            Some(to) => match self.group_session.encrypt_direct(to, &body.serialize()) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.notify(
+                        MessageKind::Error,
+                        format!("Could not change the message: {}", e),
+                    );
+                    return false;
+                }
+            },
+        };
+        if !self.send_frame(frame) {
+            return false;
+        }
+        // direct threads only live as long as the session
+        let revised = self.with_own_message(id, |cm, to| {
+            cm.revise(revision, text);
+            (cm.content.clone(), to.is_none().then(|| cm.clone()))
+        });
+        let Some((content, revised)) = revised else {
+            return true;
+        };
+        self.reformat(id, &content);
+        if let Some(cm) = revised {
+            self.save_revision(&cm);
+            self.reindex();
+        }
+        true
+    }
+
+    // an edit, delete or reaction from `author`, for a message in the room or, when
+    // `direct`, in our direct thread with them
+    fn apply_change(&mut self, author: &str, direct: bool, body: MessageBody) {
+        // ours were applied when we sent them
+        if author == self.name {
+            return;
+        }
+        let peer = direct.then_some(author);
+        match body {
+            MessageBody::Edit { id, text } => {
+                self.apply_revision(author, peer, &id, Revision::Edited, text)
+            }
+            MessageBody::Delete { id } => {
+                self.apply_revision(author, peer, &id, Revision::Deleted, String::new())
+            }
+            MessageBody::Reaction { id, emoji, added } => {
+                if self.can_react_to(peer, &id) {
+                    self.reactions
+                        .entry(id)
+                        .or_default()
+                        .set(&emoji, author, added);
+                }
+            }
+            MessageBody::Chat(_) => {}
+        }
+    }
+
+    fn apply_revision(
+        &mut self,
+        author: &str,
+        peer: Option<&str>,
+        id: &str,
+        revision: Revision,
+        text: String,
+    ) {
+        // nobody gets to change what someone else wrote
+        let sender_id = self.group_session.peer_fingerprint(author);
+        let is_target = |cm: &ConversationMessage| {
+            cm.id == id
+                && !cm.is_own()
+                && cm.sender_name == author
+                && cm.sender_id == sender_id
+                && !cm.is_deleted()
+        };
+        if peer.is_some() {
+            let thread = self.direct_threads.get_mut(author);
+            let content = thread
+                .and_then(|thread| thread.messages.iter_mut().find(|cm| is_target(cm)))
+                .map(|cm| {
+                    cm.revise(revision, text);
+                    cm.content.clone()
+                });
+            if let Some(content) = content {
+                self.reformat(id, &content);
+            }
+            return;
+        }
+        let revised = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|cm| is_target(cm))
+            .map(|cm| {
+                cm.revise(revision, text);
+                cm.clone()
+            });
+        if let Some(cm) = revised {
+            self.reformat(id, &cm.content);
+            self.save_revision(&cm);
+            self.reindex();
+        }
+    }
+
+    // whether a message that is still there has this id, in the room or, with `peer`,
+    // in our direct thread with them
+    fn can_react_to(&self, peer: Option<&str>, id: &str) -> bool {
+        let live = |cm: &ConversationMessage| {
+            cm.id == id && cm.kind == MessageKind::Chat && !cm.is_deleted()
+        };
+        match peer {
+            Some(peer) => self
+                .direct_threads
+                .get(peer)
+                .is_some_and(|thread| thread.messages.iter().any(live)),
+            None => self.messages.lock().unwrap().iter().any(live),
+        }
+    }
+
+    // adds our reaction to a message on screen, or takes it back if it is there
+    fn toggle_reaction(&mut self, id: String, emoji: String) {
+        let peer = self.direct_view.clone();
+        if !self.can_react_to(peer.as_deref(), &id) {
+            return;
+        }
+        let added = !self
+            .reactions
+            .get(&id)
+            .is_some_and(|reactions| reactions.has(&emoji, &self.name));
+        let body = MessageBody::Reaction {
+            id: id.clone(),
+            emoji: emoji.clone(),
+            added,
+        };
+        let frame = match &peer {
+            None => self.group_session.encrypt(&body.serialize()),
+            Some(peer) => match self.group_session.encrypt_direct(peer, &body.serialize()) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.notify(MessageKind::Error, format!("Could not react: {}", e));
+                    return;
+                }
+            },
+        };
+        if self.send_frame(frame) {
+            self.reactions
+                .entry(id)
+                .or_default()
+                .set(&emoji, &self.name, added);
+        }
+    }
+
+    fn receive_direct(&mut self, peer: String, cm: ConversationMessage) {
+        let on_screen = self.active && self.direct_view.as_ref() == Some(&peer);
+        let thread = self.direct_threads.entry(peer).or_default();
+        if thread.messages.iter().any(|known| known.id == cm.id) {
+            return;
+        }
+        self.conversation_message_manager
+            .assign_color(&color_key(&cm));
+        if let Some(blocks) = format::parse(&cm.content) {
+            self.formatted.insert(cm.id.clone(), blocks);
+        }
+        thread.messages.push(cm);
+        if !on_screen {
+            thread.unread += 1;
+        }
+    }
+
+    // to the member whose thread is on screen, or else to the room, as a reply if
+    // one was started
+    fn send_here(&mut self, text: String) -> Task<ChatViewMessage> {
+        let reply_to = self.replying_to.take();
+        match self.direct_view.clone() {
+            Some(peer) => self.send_direct(peer, text, reply_to),
+            None => self.send_text(text, reply_to),
+        }
+    }
+
+    // like `send_text`, to one member
+    fn send_direct(
+        &mut self,
+        to: String,
+        text: String,
+        reply_to: Option<String>,
+    ) -> Task<ChatViewMessage> {
+        if to == self.name || self.group_session.peer_fingerprint(&to).is_none() {
+            self.notify(
+                MessageKind::Error,
+                format!("{} is not a member of this room we can write to", to),
+            );
+            return Task::none();
+        }
+        let id = message::new_message_id();
+        let cm = ConversationMessage {
+            id: id.clone(),
+            kind: MessageKind::Chat,
+            sender_id: Some(self.group_session.fingerprint()),
+            sender_name: self.name.clone(),
+            content: text,
+            sent_at: Some(timestamp::unix_now()),
+            received_at: timestamp::unix_now(),
+            delivery: DeliveryState::Pending,
+            reply_to,
+            revision: Revision::Original,
+        };
+        self.reformat(&id, &cm.content);
+        self.direct_threads.entry(to).or_default().messages.push(cm);
+        self.last_active_at = Instant::now();
+        self.refresh_presence();
+        self.send_chat(&id)
+    }
+
+    // tells the room we are typing at most every TYPING_RESEND, and that we stopped
+    // once the input is cleared or left alone for TYPING_IDLE
+    fn note_input(&mut self) -> Task<ChatViewMessage> {
+        self.last_active_at = Instant::now();
+        self.refresh_presence();
+        // typing in a direct thread is nobody else's business, and fixing a message
+        // is not writing a new one
+        if !self.send_typing || self.direct_view.is_some() || self.editing.is_some() {
+            return Task::none();
+        }
+        // commands stay between us and the client
+        if self.current_message.trim().is_empty() || commands::is_command(&self.current_message) {
+            self.stop_typing();
+            return Task::none();
+        }
+        let now = Instant::now();
+        self.last_input_at = Some(now);
+        if self
+            .typing_sent_at
+            .is_none_or(|sent_at| now.duration_since(sent_at) >= TYPING_RESEND)
+        {
+            self.typing_sent_at = Some(now);
+            self.send_typing_state(true);
+        }
+        Task::perform(tokio::time::sleep(TYPING_IDLE), |_| {
+            ChatViewMessage::TypingIdle
+        })
+    }
+
+    fn stop_typing(&mut self) {
+        self.last_input_at = None;
+        if self.typing_sent_at.take().is_some() {
+            self.send_typing_state(false);
+        }
+    }
+
+    // best effort, a lost typing notice is not worth telling anyone about
+    fn send_typing_state(&self, typing: bool) {
+        if let Some(session) = &self.session {
+            session.send(ClientFrame::Typing(TypingNotice {
+                name: self.name.clone(),
+                typing,
+            }));
+        }
+    }
+
+    fn send_frame(&mut self, frame: ClientFrame) -> bool {
+        let sent = match &self.session {
+            Some(session) => session.send(frame),
+            None => false,
+        };
+        if !sent {
+            self.push_message(ConversationMessage::notice(
+                MessageKind::Error,
+                String::from("Not connected, message was not sent"),
+            ));
+        }
+        sent
+    }
+
+    // shows the message at once and sends it in the background
+    fn send_text(&mut self, text: String, reply_to: Option<String>) -> Task<ChatViewMessage> {
+        let id = message::new_message_id();
+        let cm = ConversationMessage {
+            id: id.clone(),
+            kind: MessageKind::Chat,
+            sender_id: Some(self.group_session.fingerprint()),
+            sender_name: self.name.clone(),
+            content: text,
+            sent_at: Some(timestamp::unix_now()),
+            received_at: timestamp::unix_now(),
+            delivery: DeliveryState::Pending,
+            reply_to,
+            revision: Revision::Original,
+        };
+        self.push_message(cm);
+        self.last_active_at = Instant::now();
+        self.refresh_presence();
+        self.send_chat(&id)
+    }
+
+    // the server knows us by the name we joined with, so a new name means leaving
+    // and joining again; members see us leave and come back under the new one
+    fn rename(&mut self, name: String) -> bool {
+        if self.session.is_none() {
+            return false;
+        }
+        self.stop_voice();
+        self.stop_typing();
+        if let Some(session) = self.session.take() {
+            session.send(ClientFrame::LeaveRoom {
+                room_id: self.room_id.clone(),
+                name: self.name.clone(),
+            });
+            tokio::spawn(session.close());
+        }
+        let identity = self.group_session.identity().clone();
+        self.group_session = GroupSession::new(name.clone(), self.room_key.clone(), identity);
+        self.roster = Roster::new();
+        self.roster.joined(&name);
+        self.name = name;
+        // the room moves to a new key, and the subscription started for the new
+        // session id brings the session back up under the new name
+        self.events = None;
+        self.session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    // only what is on screen goes, the history on disk is left alone
+    fn clear(&mut self) {
+        self.cancel_compose();
+        self.thread_view = None;
+        if let Some(peer) = &self.direct_view {
+            if let Some(thread) = self.direct_threads.get_mut(peer) {
+                thread.messages.clear();
+            }
+            return;
+        }
+        self.messages.lock().unwrap().clear();
+        self.outbox.clear();
+        self.search_index = SearchIndex::new();
+        if let Some(search_panel) = &mut self.search_panel {
+            search_panel.selected = None;
+            search_panel.run(&self.search_index);
+        }
+    }
+
+    // drops out of the voice channel locally, the server is told by the caller
+    fn stop_voice(&mut self) {
+        if let VoiceState::Active(voice_session) =
+            std::mem::replace(&mut self.voice, VoiceState::Off)
+        {
+            voice_session.stop();
+        }
+        self.refresh_presence();
+    }
+
+    // sends LeaveRoom and hands back a future that resolves once the session task
+    // has flushed it and closed the socket
+    pub fn leave(&mut self) -> Option<impl Future<Output = ()> + Send + 'static> {
+        self.stop_voice();
+        let session = self.session.take()?;
+        session.send(ClientFrame::LeaveRoom {
+            room_id: self.room_id.clone(),
+            name: self.name.clone(),
+        });
+        self.connection = None;
+        self.events = None;
+        self.connection_status = ConnectionStatus::Offline(String::from("Disconnected"));
+        Some(session.close())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ChatViewMessage {
-    StartReader(iced::futures::channel::mpsc::Sender<String>),
-    ReceivedMessage(String),
+    StartReader(Sender<SessionEvent>),
+    ReceivedMessage(SessionEvent),
     SendMessage(String),
+    // a member's name was clicked
+    OpenDirect(String),
+    CloseDirect,
+    // id of the message to answer
+    ReplyTo(String),
+    CancelReply,
+    // ids of our own messages
+    EditMessage(String),
+    CancelEdit,
+    DeleteMessage(String),
+    // message id and emoji
+    ToggleReaction(String, String),
+    // id of the message to pick a reaction for
+    ShowReactionPicker(String),
+    // id of any message in the thread to show
+    OpenThread(String),
+    CloseThread,
+    // tab in the message input
+    CompleteInput,
+    // id of one of our messages the server has not confirmed in time
+    DeliveryTimedOut(String),
+    RetryMessage(String),
     CurrentMessageChanged(String),
+    // fired a while after each keystroke, to notice we stopped typing
+    TypingIdle,
+    // drops typing notices that ran out
+    TypingTick,
     JoinVoiceChannel,
+    VoiceStarted(Result<VoiceSession, VoiceError>),
+    ToggleMute,
+    ToggleDeafen,
+    LeaveVoiceChannel,
+    Reconnect,
     Disconnect,
+    // notices when we went idle
+    PresenceTick,
+    ToggleMembers,
+    ToggleRawText,
+    // a link in a formatted message was clicked
+    OpenLink(markdown::Url),
+    ToggleSearch,
+    SearchTextChanged(String),
+    SearchSenderChanged(String),
+    SearchFromChanged(String),
+    SearchToChanged(String),
+    // position of the message to scroll to
+    SelectSearchHit(usize),
 }
 
 pub enum ChatViewAction {
     None,
-    Disconnect,
+    Disconnect(Account),
+    // another room, with the same account
+    JoinRoom { account: Account, room_id: String },
+    Run(Task<ChatViewMessage>),
 }
 
 pub fn update(app_state: &mut ChatViewState, message: ChatViewMessage) -> ChatViewAction {
     match message {
-        ChatViewMessage::StartReader(mut sx) => {
+        ChatViewMessage::StartReader(sx) => {
             println!("Message::StartReader received");
-            let mut tcp_stream = app_state.tcp_stream.try_clone().unwrap();
-            tokio::spawn(async move {
-                let key = b"thisIsASecretKey";
-                loop {
-                    let mut buf = [0u8; 1024];
-                    let bytes_read = tcp_stream.read(&mut buf).unwrap();
-
-                    let mut m = str::from_utf8(&buf[..bytes_read]).unwrap().to_string();
-                    let split_message = m.split(' ').collect::<Vec<_>>();
-                    let type_of_message = split_message[0];
-
-                    if type_of_message == "NORMAL_MESSAGE" {
-                        let b64decoded_message = base64::prelude::BASE64_STANDARD
-                            .decode(split_message[1])
-                            .unwrap();
-                        m = format!("{} {}", split_message[0], decrypt(b64decoded_message, key));
+            app_state.events = Some(sx);
+            app_state.start_session();
+            ChatViewAction::Run(app_state.flush_outbox())
+        }
+        ChatViewMessage::ReceivedMessage(SessionEvent::Status(status)) => {
+            let reconnected = status == ConnectionStatus::Connected
+                && app_state.connection_status != ConnectionStatus::Connected;
+            if let ConnectionStatus::Offline(_) = status {
+                // the server forgets our voice token with the connection
+                if !matches!(app_state.voice, VoiceState::Off) {
+                    app_state.stop_voice();
+                    app_state.push_message(ConversationMessage::notice(
+                        MessageKind::Voice,
+                        String::from("You left the voice channel, the connection was lost"),
+                    ));
+                }
+                // whatever the session task still held went down with it
+                let stranded = app_state
+                    .pending_ids()
+                    .into_iter()
+                    .filter(|id| !app_state.outbox.contains(id))
+                    .collect::<Vec<String>>();
+                app_state.outbox.extend(stranded);
+            }
+            app_state.connection_status = status;
+            if reconnected {
+                app_state.announce();
+                return ChatViewAction::Run(app_state.flush_outbox());
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::ReceivedMessage(SessionEvent::Delivered(id)) => {
+            app_state.mark_delivered(&id);
+            ChatViewAction::None
+        }
+        ChatViewMessage::DeliveryTimedOut(id) => {
+            if app_state.delivery_of(&id) != Some(DeliveryState::Pending)
+                || app_state.outbox.contains(&id)
+            {
+                return ChatViewAction::None;
+            }
+            // while reconnecting the session still holds the frame, give it time
+            if app_state.connection_status != ConnectionStatus::Connected {
+                return ChatViewAction::Run(delivery_timer(id));
+            }
+            app_state.set_delivery(&id, DeliveryState::Failed);
+            ChatViewAction::None
+        }
+        ChatViewMessage::RetryMessage(id) => {
+            if app_state.delivery_of(&id) != Some(DeliveryState::Failed) {
+                return ChatViewAction::None;
+            }
+            ChatViewAction::Run(app_state.send_chat(&id))
+        }
+        ChatViewMessage::ReceivedMessage(SessionEvent::Frame(server_frame)) => {
+            let notice = ConversationMessage::notice;
+            let cm = match server_frame {
+                Ok(ServerFrame::NormalMessage(payload)) => {
+                    match decrypt(&payload, app_state.room_key.bytes()) {
+                        Ok(plaintext) => Some(legacy_message(plaintext)),
+                        Err(e) => Some(notice(
+                            MessageKind::Error,
+                            format!("Could not decrypt message ({})", e),
+                        )),
                     }
-
-                    match sx.send(m).await {
-                        Ok(_) => {}
-                        Err(_e) => {
-                            break;
+                }
+                Ok(ServerFrame::GroupMessage(payload)) => {
+                    match app_state.group_session.decrypt(&payload) {
+                        Ok(plaintext) => {
+                            let body = MessageBody::parse(
+                                &plaintext,
+                                &payload.name,
+                                message::new_message_id,
+                            );
+                            let MessageBody::Chat(chat_payload) = body else {
+                                app_state.apply_change(&payload.name, false, body);
+                                return ChatViewAction::None;
+                            };
+                            app_state.typing.remove(&payload.name);
+                            if app_state.has_message(&chat_payload.id) {
+                                // our own message relayed back, or a retry of one we
+                                // already have
+                                if payload.name == app_state.name {
+                                    app_state.mark_delivered(&chat_payload.id);
+                                }
+                                return ChatViewAction::None;
+                            }
+                            Some(ConversationMessage {
+                                id: chat_payload.id,
+                                kind: MessageKind::Chat,
+                                sender_id: app_state.group_session.peer_fingerprint(&payload.name),
+                                sender_name: payload.name,
+                                content: chat_payload.text,
+                                sent_at: chat_payload.sent_at,
+                                received_at: timestamp::unix_now(),
+                                delivery: DeliveryState::Received,
+                                reply_to: chat_payload.reply_to,
+                                revision: Revision::Original,
+                            })
                         }
-                    };
+                        Err(e) => Some(notice(
+                            MessageKind::Error,
+                            format!("Could not decrypt message ({})", e),
+                        )),
+                    }
+                }
+                Ok(ServerFrame::DirectMessage(payload)) => {
+                    match app_state.group_session.decrypt_direct(&payload) {
+                        Ok(Some(plaintext)) => {
+                            let body = MessageBody::parse(
+                                &plaintext,
+                                &payload.name,
+                                message::new_message_id,
+                            );
+                            let MessageBody::Chat(chat_payload) = body else {
+                                app_state.apply_change(&payload.name, true, body);
+                                return ChatViewAction::None;
+                            };
+                            let cm = ConversationMessage {
+                                id: chat_payload.id,
+                                kind: MessageKind::Chat,
+                                sender_id: app_state.group_session.peer_fingerprint(&payload.name),
+                                sender_name: payload.name.clone(),
+                                content: chat_payload.text,
+                                sent_at: chat_payload.sent_at,
+                                received_at: timestamp::unix_now(),
+                                delivery: DeliveryState::Received,
+                                reply_to: chat_payload.reply_to,
+                                revision: Revision::Original,
+                            };
+                            app_state.receive_direct(payload.name, cm);
+                            None
+                        }
+                        Ok(None) => None,
+                        Err(e) => Some(notice(
+                            MessageKind::Error,
+                            format!(
+                                "Could not decrypt a direct message from {} ({})",
+                                payload.name, e
+                            ),
+                        )),
+                    }
+                }
+                Ok(ServerFrame::PublicKey(announcement)) => {
+                    let name = announcement.name.clone();
+                    match app_state.group_session.handle_public_key(announcement) {
+                        Ok(frames) => {
+                            for frame in frames {
+                                app_state.send_frame(frame);
+                            }
+                            let key = app_state.member_key(&name);
+                            app_state.conversation_message_manager.assign_color(&key);
+                            app_state
+                                .roster
+                                .joined(&name)
+                                .then(|| notice(MessageKind::Join, format!("{} is here", name)))
+                        }
+                        Err(e) => Some(notice(MessageKind::Error, e.to_string())),
+                    }
+                }
+                Ok(ServerFrame::SenderKey(delivery)) => {
+                    match app_state.group_session.handle_sender_key(delivery) {
+                        Ok(()) => None,
+                        Err(e) => Some(notice(
+                            MessageKind::Error,
+                            format!("Key exchange failed: {}", e),
+                        )),
+                    }
+                }
+                Ok(ServerFrame::Typing(notice)) => {
+                    if notice.name == app_state.name {
+                        None
+                    } else if notice.typing {
+                        app_state
+                            .typing
+                            .insert(notice.name, Instant::now() + TYPING_EXPIRY);
+                        None
+                    } else {
+                        app_state.typing.remove(&notice.name);
+                        None
+                    }
+                }
+                Ok(ServerFrame::MemberList(names)) => {
+                    for frame in app_state.group_session.retain(&names) {
+                        app_state.send_frame(frame);
+                    }
+                    app_state.roster.replace(names);
+                    // whatever the server thinks, we are here
+                    app_state
+                        .roster
+                        .set_presence(&app_state.name, app_state.presence);
+                    None
+                }
+                Ok(ServerFrame::MemberJoined(name)) => {
+                    let joined = name != app_state.name && app_state.roster.joined(&name);
+                    joined.then(|| notice(MessageKind::Join, format!("{} joined", name)))
+                }
+                Ok(ServerFrame::MemberLeft(name)) => {
+                    app_state.typing.remove(&name);
+                    for frame in app_state.group_session.forget(&name) {
+                        app_state.send_frame(frame);
+                    }
+                    let left = name != app_state.name && app_state.roster.left(&name);
+                    left.then(|| notice(MessageKind::Leave, format!("{} left", name)))
+                }
+                Ok(ServerFrame::Presence(presence_notice)) => {
+                    if presence_notice.name != app_state.name {
+                        app_state
+                            .roster
+                            .set_presence(&presence_notice.name, presence_notice.presence);
+                    }
+                    None
+                }
+                Ok(ServerFrame::VoiceOffer(offer)) => {
+                    if !matches!(app_state.voice, VoiceState::Joining) {
+                        return ChatViewAction::None;
+                    }
+                    let host = app_state.server_address.host.clone();
+                    let room_key = *app_state.room_key.bytes();
+                    let audio = app_state.audio.clone();
+                    return ChatViewAction::Run(Task::perform(
+                        VoiceSession::start(host, offer, room_key, audio),
+                        ChatViewMessage::VoiceStarted,
+                    ));
+                }
+                Ok(ServerFrame::System(s)) | Ok(ServerFrame::Welcome(s)) => {
+                    Some(notice(MessageKind::System, s))
+                }
+                Ok(ServerFrame::RoomJoinSuccess(s)) => Some(notice(MessageKind::Join, s)),
+                Ok(ServerFrame::RoomJoinFailure(s)) => Some(notice(MessageKind::Error, s)),
+                Err(e) => Some(notice(
+                    MessageKind::Error,
+                    format!("Ignored a malformed frame: {}", e),
+                )),
+            };
+            if let Some(cm) = cm {
+                let on_screen = app_state.active && app_state.direct_view.is_none();
+                if !on_screen && cm.kind == MessageKind::Chat {
+                    app_state.unread += 1;
                 }
+                app_state.push_message(cm);
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::SendMessage(s) => {
+            if s.trim().is_empty() {
+                return ChatViewAction::None;
+            }
+            // the new text goes as it is, commands included, and stays in the input
+            // if it could not be sent
+            if let Some(id) = app_state.editing.clone() {
+                if app_state.revise_own(&id, Revision::Edited, s.trim().to_string()) {
+                    app_state.editing = None;
+                    app_state.current_message.clear();
+                }
+                return ChatViewAction::None;
+            }
+            let input = match commands::parse(&s) {
+                Ok(input) => input,
+                // left in the input to fix
+                Err(e) => {
+                    app_state.notify(MessageKind::Error, e.to_string());
+                    return ChatViewAction::None;
+                }
+            };
+            app_state.current_message.clear();
+            app_state.stop_typing();
+            match input {
+                Input::Text(text) => ChatViewAction::Run(app_state.send_here(text)),
+                Input::Command(command) => run_command(app_state, command),
+            }
+        }
+        ChatViewMessage::OpenDirect(peer) => {
+            if peer != app_state.name {
+                app_state.show_direct(Some(peer));
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::CloseDirect => {
+            app_state.show_direct(None);
+            ChatViewAction::None
+        }
+        ChatViewMessage::ReplyTo(id) => {
+            app_state.cancel_compose();
+            app_state.replying_to = Some(id);
+            ChatViewAction::Run(text_input::focus(app_state.message_input_id.clone()))
+        }
+        ChatViewMessage::CancelReply => {
+            app_state.replying_to = None;
+            ChatViewAction::None
+        }
+        ChatViewMessage::EditMessage(id) => {
+            let content = app_state.with_visible_messages(|messages| {
+                messages
+                    .iter()
+                    .find(|cm| cm.id == id && cm.is_own() && !cm.is_deleted())
+                    .map(|cm| cm.content.clone())
             });
+            let Some(content) = content else {
+                return ChatViewAction::None;
+            };
+            app_state.stop_typing();
+            app_state.replying_to = None;
+            app_state.editing = Some(id);
+            app_state.current_message = content;
+            let input_id = app_state.message_input_id.clone();
+            ChatViewAction::Run(Task::batch([
+                text_input::focus(input_id.clone()),
+                text_input::move_cursor_to_end(input_id),
+            ]))
+        }
+        ChatViewMessage::ToggleReaction(id, emoji) => {
+            app_state.reaction_picker = None;
+            app_state.toggle_reaction(id, emoji);
+            ChatViewAction::None
+        }
+        ChatViewMessage::ShowReactionPicker(id) => {
+            app_state.reaction_picker = match app_state.reaction_picker {
+                Some(ref open) if *open == id => None,
+                _ => Some(id),
+            };
+            ChatViewAction::None
+        }
+        ChatViewMessage::CancelEdit => {
+            app_state.cancel_compose();
+            ChatViewAction::None
+        }
+        ChatViewMessage::DeleteMessage(id) => {
+            if app_state.revise_own(&id, Revision::Deleted, String::new())
+                && app_state.editing.as_ref() == Some(&id)
+            {
+                app_state.cancel_compose();
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::OpenThread(id) => {
+            let root = app_state
+                .with_visible_messages(|messages| message::thread_root(messages, &id).to_string());
+            app_state.thread_view = Some(root);
+            ChatViewAction::None
+        }
+        ChatViewMessage::CloseThread => {
+            app_state.thread_view = None;
+            ChatViewAction::None
+        }
+        ChatViewMessage::CompleteInput => {
+            let members = app_state.roster.iter().map(|(name, _)| name);
+            match commands::complete(&app_state.current_message, members) {
+                Some(completed) => {
+                    app_state.current_message = completed;
+                    ChatViewAction::Run(text_input::move_cursor_to_end(
+                        app_state.message_input_id.clone(),
+                    ))
+                }
+                None => ChatViewAction::None,
+            }
+        }
+        ChatViewMessage::CurrentMessageChanged(s) => {
+            app_state.current_message = s;
+            ChatViewAction::Run(app_state.note_input())
+        }
+        ChatViewMessage::TypingIdle => {
+            let idle = app_state
+                .last_input_at
+                .is_some_and(|last_input_at| last_input_at.elapsed() >= TYPING_IDLE);
+            if idle {
+                app_state.stop_typing();
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::TypingTick => {
+            let now = Instant::now();
+            app_state.typing.retain(|_, expires_at| *expires_at > now);
+            ChatViewAction::None
+        }
+        ChatViewMessage::JoinVoiceChannel => {
+            if !matches!(app_state.voice, VoiceState::Off) {
+                return ChatViewAction::None;
+            }
+            let frame = ClientFrame::JoinVoiceChannel {
+                name: app_state.name.clone(),
+            };
+            if app_state.send_frame(frame) {
+                app_state.voice = VoiceState::Joining;
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::VoiceStarted(Ok(voice_session)) => {
+            // left again before the session was up
+            if !matches!(app_state.voice, VoiceState::Joining) {
+                voice_session.stop();
+                return ChatViewAction::None;
+            }
+            voice_session.set_muted(app_state.muted);
+            voice_session.set_deafened(app_state.deafened);
+            app_state.voice = VoiceState::Active(voice_session);
+            app_state.push_message(ConversationMessage::notice(
+                MessageKind::Voice,
+                String::from("You joined the voice channel"),
+            ));
+            app_state.refresh_presence();
+            ChatViewAction::None
+        }
+        ChatViewMessage::VoiceStarted(Err(e)) => {
+            if !matches!(app_state.voice, VoiceState::Joining) {
+                return ChatViewAction::None;
+            }
+            app_state.voice = VoiceState::Off;
+            // the server counts us in until told otherwise
+            if let Some(session) = &app_state.session {
+                session.send(ClientFrame::LeaveVoiceChannel {
+                    name: app_state.name.clone(),
+                });
+            }
+            app_state.push_message(ConversationMessage::notice(
+                MessageKind::Error,
+                format!("Could not join the voice channel: {}", e),
+            ));
+            ChatViewAction::None
+        }
+        ChatViewMessage::ToggleMute => {
+            app_state.muted = !app_state.muted;
+            if let VoiceState::Active(voice_session) = &app_state.voice {
+                voice_session.set_muted(app_state.muted);
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::ToggleDeafen => {
+            app_state.deafened = !app_state.deafened;
+            if let VoiceState::Active(voice_session) = &app_state.voice {
+                voice_session.set_deafened(app_state.deafened);
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::LeaveVoiceChannel => {
+            if matches!(app_state.voice, VoiceState::Off) {
+                return ChatViewAction::None;
+            }
+            if let Some(session) = &app_state.session {
+                session.send(ClientFrame::LeaveVoiceChannel {
+                    name: app_state.name.clone(),
+                });
+            }
+            app_state.stop_voice();
+            app_state.push_message(ConversationMessage::notice(
+                MessageKind::Voice,
+                String::from("You left the voice channel"),
+            ));
+            ChatViewAction::None
+        }
+        ChatViewMessage::Reconnect => {
+            if let ConnectionStatus::Offline(_) = app_state.connection_status {
+                app_state.start_session();
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::Disconnect => {
+            app_state.push_message(ConversationMessage::notice(
+                MessageKind::Leave,
+                String::from("You left the room"),
+            ));
+            if let Some(closed) = app_state.leave() {
+                tokio::spawn(closed);
+            }
+            ChatViewAction::Disconnect(app_state.account())
+        }
+        ChatViewMessage::PresenceTick => {
+            app_state.refresh_presence();
             ChatViewAction::None
         }
-        ChatViewMessage::ReceivedMessage(s) => {
-            let mut messages = app_state.messages.lock().unwrap();
-            let cm = app_state.conversation_message_manager.cm_from_string(s);
-            messages.push(cm);
-            drop(messages);
+        ChatViewMessage::ToggleMembers => {
+            app_state.show_members = !app_state.show_members;
             ChatViewAction::None
         }
-        ChatViewMessage::SendMessage(s) => {
-            let message = s.clone();
-            let s = format!("{} > {}", app_state.name, s.trim().to_string());
-            if s.len() == 0 {
+        ChatViewMessage::ToggleRawText => {
+            app_state.show_raw = !app_state.show_raw;
+            ChatViewAction::None
+        }
+        ChatViewMessage::OpenLink(url) => {
+            if !format::is_safe_link(&url) {
+                app_state.notify(MessageKind::Error, format!("Not opening {}", url));
                 return ChatViewAction::None;
             }
-            let mut tcp_stream = app_state.tcp_stream.try_clone().unwrap();
-
-            let key = b"thisIsASecretKey";
-            let encrypted_message = encrypt(s.as_str(), key);
-
-            // attach the type of the message
-            let encrypted_message_string =
-                base64::prelude::BASE64_STANDARD.encode(encrypted_message);
-            let final_message = format!("NORMAL_MESSAGE {}", encrypted_message_string);
+            if let Err(e) = open::that_detached(url.as_str()) {
+                app_state.notify(MessageKind::Error, format!("Could not open {}: {}", url, e));
+            }
+            ChatViewAction::None
+        }
+        ChatViewMessage::ToggleSearch => {
+            app_state.search_panel = match app_state.search_panel {
+                Some(_) => None,
+                None => Some(SearchPanel::default()),
+            };
+            ChatViewAction::None
+        }
+        ChatViewMessage::SearchTextChanged(s) => {
+            update_search(app_state, |search_panel| search_panel.text = s);
+            ChatViewAction::None
+        }
+        ChatViewMessage::SearchSenderChanged(s) => {
+            update_search(app_state, |search_panel| search_panel.sender = s);
+            ChatViewAction::None
+        }
+        ChatViewMessage::SearchFromChanged(s) => {
+            update_search(app_state, |search_panel| search_panel.from = s);
+            ChatViewAction::None
+        }
+        ChatViewMessage::SearchToChanged(s) => {
+            update_search(app_state, |search_panel| search_panel.to = s);
+            ChatViewAction::None
+        }
+        ChatViewMessage::SelectSearchHit(position) => {
+            let Some(search_panel) = &mut app_state.search_panel else {
+                return ChatViewAction::None;
+            };
+            search_panel.selected = Some(position);
+            // hits are in the room, not in direct threads
+            app_state.show_direct(None);
+            let id = match app_state.messages.lock().unwrap().get(position) {
+                Some(cm) => cm.id.clone(),
+                None => return ChatViewAction::None,
+            };
+            ChatViewAction::Run(scroll_to_row(
+                app_state.messages_scroll_id.clone(),
+                message_row_id(&id),
+            ))
+        }
+    }
+}
 
-            tcp_stream.write_all(final_message.as_bytes()).unwrap();
-            {
-                app_state.messages.lock().unwrap().push(
-                    app_state
-                        .conversation_message_manager
-                        .format_conversation_message("You".to_string(), message),
+fn run_command(app_state: &mut ChatViewState, command: Command) -> ChatViewAction {
+    match command {
+        Command::Nick(name) => {
+            if name == app_state.name {
+                return ChatViewAction::None;
+            }
+            if app_state.rename(name.clone()) {
+                app_state.notify(
+                    MessageKind::System,
+                    format!("You are now known as {}", name),
+                );
+            } else {
+                app_state.notify(
+                    MessageKind::Error,
+                    String::from("Not connected, your name was not changed"),
                 );
             }
-            app_state.current_message.clear();
             ChatViewAction::None
         }
-        ChatViewMessage::CurrentMessageChanged(s) => {
-            app_state.current_message = s;
+        Command::Join(room_id) => ChatViewAction::JoinRoom {
+            account: app_state.account(),
+            room_id,
+        },
+        Command::Leave => update(app_state, ChatViewMessage::Disconnect),
+        Command::Me(action) => {
+            ChatViewAction::Run(app_state.send_here(commands::emote_text(&action)))
+        }
+        Command::Msg { to, text } => {
+            let task = app_state.send_direct(to.clone(), text, None);
+            if app_state.direct_threads.contains_key(&to) {
+                app_state.show_direct(Some(to));
+            }
+            ChatViewAction::Run(task)
+        }
+        Command::Clear => {
+            app_state.clear();
             ChatViewAction::None
         }
-        ChatViewMessage::JoinVoiceChannel => {
-            let mut tcp_stream = app_state.tcp_stream.try_clone().unwrap();
-            tcp_stream.write_all(format!("JOIN_VOICE_CHANNEL_MESSAGE {}", app_state.name).as_bytes()).unwrap();
+        Command::Voice => match app_state.voice {
+            VoiceState::Off => update(app_state, ChatViewMessage::JoinVoiceChannel),
+            VoiceState::Joining | VoiceState::Active(_) => {
+                update(app_state, ChatViewMessage::LeaveVoiceChannel)
+            }
+        },
+        Command::Help => {
+            for spec in COMMANDS {
+                app_state.notify(
+                    MessageKind::System,
+                    format!("{}  {}", spec.usage, spec.summary),
+                );
+            }
             ChatViewAction::None
         }
-        ChatViewMessage::Disconnect => {
-            let tcp_stream = app_state.tcp_stream.try_clone().unwrap();
-            match tcp_stream.shutdown(std::net::Shutdown::Both) {
-                Ok(_) => {}
-                Err(e) => println!("Shutdown failed {}", e),
+    }
+}
+
+fn delivery_timer(id: String) -> Task<ChatViewMessage> {
+    Task::perform(
+        async move {
+            tokio::time::sleep(DELIVERY_TIMEOUT).await;
+            id
+        },
+        ChatViewMessage::DeliveryTimedOut,
+    )
+}
+
+fn message_row_id(id: &str) -> container::Id {
+    container::Id::new(format!("message-{}", id))
+}
+
+// rows differ in height, so where one starts is read off the laid out conversation
+// and the scrollable is moved to put it at the top
+fn scroll_to_row(scrollable_id: scrollable::Id, row_id: container::Id) -> Task<ChatViewMessage> {
+    struct ScrollToRow {
+        scrollable: widget::Id,
+        row: widget::Id,
+        content_top: Option<f32>,
+        row_top: Option<f32>,
+    }
+
+    impl Operation for ScrollToRow {
+        fn container(
+            &mut self,
+            id: Option<&widget::Id>,
+            bounds: Rectangle,
+            operate_on_children: &mut dyn FnMut(&mut dyn Operation),
+        ) {
+            if id == Some(&self.row) {
+                self.row_top = Some(bounds.y);
+                return;
+            }
+            operate_on_children(self);
+        }
+
+        fn scrollable(
+            &mut self,
+            _state: &mut dyn operation::Scrollable,
+            id: Option<&widget::Id>,
+            _bounds: Rectangle,
+            content_bounds: Rectangle,
+            _translation: Vector,
+        ) {
+            if id == Some(&self.scrollable) {
+                self.content_top = Some(content_bounds.y);
+            }
+        }
+
+        fn finish(&self) -> operation::Outcome<()> {
+            let (Some(content_top), Some(row_top)) = (self.content_top, self.row_top) else {
+                return operation::Outcome::None;
+            };
+            let offset = AbsoluteOffset {
+                x: 0.0,
+                y: row_top - content_top,
             };
-            ChatViewAction::Disconnect
+            operation::Outcome::Chain(Box::new(operation::scrollable::scroll_to(
+                self.scrollable.clone(),
+                offset,
+            )))
         }
     }
+
+    widget::operate(ScrollToRow {
+        scrollable: scrollable_id.into(),
+        row: row_id.into(),
+        content_top: None,
+        row_top: None,
+    })
+    .discard()
+}
+
+fn update_search(app_state: &mut ChatViewState, edit: impl FnOnce(&mut SearchPanel)) {
+    if let Some(search_panel) = &mut app_state.search_panel {
+        edit(search_panel);
+        search_panel.run(&app_state.search_index);
+    }
 }
 
-pub fn view(app_state: &ChatViewState) -> Element<ChatViewMessage> {
+pub fn view(app_state: &ChatViewState) -> Element<'_, ChatViewMessage> {
     let font_size = 17;
     let mut cm_name_font = Font::with_name("clash-grotesk-variable");
     cm_name_font.weight = font::Weight::Bold;
-    let mut cm_message = Font::with_name("clash-grotesk-variable");
-    cm_message.weight = font::Weight::Normal;
 
     let messages = app_state.messages.lock().unwrap();
-    let messages_text_vec = messages
-        .iter()
-        .map(|msg| {
-            if msg.name.len() != 0 {
-                let name_text: Element<ChatViewMessage> = text(format!("{}", msg.name.trim()))
-                    .color(msg.color)
-                    .size(font_size)
-                    .font(cm_name_font)
-                    .line_height(0.6)
-                    .into();
-                let message_text: Element<ChatViewMessage> =
-                    text(format!("{}", msg.content.trim()))
-                        .color(msg.color)
-                        .size(font_size + 1)
-                        .into();
-
-                if msg.name == "You" {
-                    column![name_text, message_text]
-                        .width(Length::Fill)
-                        .align_x(Alignment::End)
-                        .padding(Padding {
-                            top: 0.0,
-                            right: 20.0,
-                            bottom: 0.0,
-                            left: 20.0,
-                        })
-                        .into()
-                } else {
-                    column![name_text, message_text]
-                        .width(Length::Fill)
-                        .padding(Padding {
-                            top: 0.0,
-                            right: 20.0,
-                            bottom: 0.0,
-                            left: 20.0,
-                        })
-                        .into()
-                }
-            } else {
-                let generic_text: Element<ChatViewMessage> = text(format!("{}", msg.content))
-                    .color(msg.color)
-                    .size(font_size)
-                    .into();
-                row![generic_text].into()
-            }
-        })
-        .collect::<Vec<Element<ChatViewMessage>>>();
+    let selected = app_state
+        .search_panel
+        .as_ref()
+        .and_then(|search_panel| search_panel.selected);
+    let cmm = &app_state.conversation_message_manager;
+    let (title, visible, selected) = match &app_state.direct_view {
+        Some(peer) => (
+            format!("Direct messages with {}", peer),
+            app_state
+                .direct_threads
+                .get(peer)
+                .map(|thread| thread.messages.as_slice())
+                .unwrap_or_default(),
+            None,
+        ),
+        None => (
+            format!("Room {}", app_state.room_id),
+            messages.as_slice(),
+            selected,
+        ),
+    };
+    let messages_text_vec = conversation_view(app_state, visible, selected, font_size);
+    let thread_panel = app_state
+        .thread_view
+        .as_ref()
+        .map(|root| thread_panel_view(app_state, visible, root, font_size));
+    let compose_bar = compose_bar_view(app_state, visible, font_size);
 
+    let search_panel = app_state
+        .search_panel
+        .as_ref()
+        .map(|search_panel| search_panel_view(search_panel, &messages, cmm, font_size));
     drop(messages);
 
     let messages_column: Element<ChatViewMessage> = Column::from_vec(messages_text_vec)
@@ -212,12 +1830,18 @@ pub fn view(app_state: &ChatViewState) -> Element<ChatViewMessage> {
         .spacing(20)
         .into();
     let scrollable_messages: Element<ChatViewMessage> = scrollable(messages_column)
+        .id(app_state.messages_scroll_id.clone())
         .height(Length::Fill)
         .width(Length::Fill)
         .into();
 
+    let placeholder = match &app_state.direct_view {
+        Some(peer) => format!("Message {}, or /help", peer),
+        None => String::from("Type a message, or /help"),
+    };
     let message_input: Element<ChatViewMessage> =
-        text_input("Type your message", &app_state.current_message)
+        text_input(&placeholder, &app_state.current_message)
+            .id(app_state.message_input_id.clone())
             .on_input(ChatViewMessage::CurrentMessageChanged)
             .on_submit(ChatViewMessage::SendMessage(
                 app_state.current_message.clone(),
@@ -226,12 +1850,16 @@ pub fn view(app_state: &ChatViewState) -> Element<ChatViewMessage> {
             .size(16)
             .into();
 
-    let send_button: Element<ChatViewMessage> = button("Send")
-        .on_press(ChatViewMessage::SendMessage(
-            app_state.current_message.clone(),
-        ))
-        .padding(10)
-        .into();
+    let send_button: Element<ChatViewMessage> = button(if app_state.editing.is_some() {
+        "Save"
+    } else {
+        "Send"
+    })
+    .on_press(ChatViewMessage::SendMessage(
+        app_state.current_message.clone(),
+    ))
+    .padding(10)
+    .into();
 
     let input_row: Element<ChatViewMessage> = Row::new()
         .push(message_input)
@@ -240,50 +1868,732 @@ pub fn view(app_state: &ChatViewState) -> Element<ChatViewMessage> {
         .height(Length::Shrink)
         .into();
 
-    let join_voice_btn: Element<ChatViewMessage> = button("Join Voice Chat")
-        .on_press(ChatViewMessage::JoinVoiceChannel)
-        .into();
+    let voice_controls: Element<ChatViewMessage> = match app_state.voice {
+        VoiceState::Off => button("Join Voice Chat")
+            .on_press(ChatViewMessage::JoinVoiceChannel)
+            .into(),
+        VoiceState::Joining => row![
+            button("Joining voice…"),
+            button("Cancel").on_press(ChatViewMessage::LeaveVoiceChannel)
+        ]
+        .spacing(10)
+        .into(),
+        VoiceState::Active(_) => row![
+            button(if app_state.muted { "Unmute" } else { "Mute" })
+                .on_press(ChatViewMessage::ToggleMute),
+            button(if app_state.deafened {
+                "Undeafen"
+            } else {
+                "Deafen"
+            })
+            .on_press(ChatViewMessage::ToggleDeafen),
+            button("Leave Voice").on_press(ChatViewMessage::LeaveVoiceChannel)
+        ]
+        .spacing(10)
+        .into(),
+    };
 
     let disconnect_btn: Element<ChatViewMessage> = button("Disconnect Room")
         .on_press(ChatViewMessage::Disconnect)
         .into();
 
+    let search_btn: Element<ChatViewMessage> = button(if search_panel.is_some() {
+        "Close Search"
+    } else {
+        "Search"
+    })
+    .on_press(ChatViewMessage::ToggleSearch)
+    .into();
+
+    let raw_btn: Element<ChatViewMessage> = button(if app_state.show_raw {
+        "Show Formatting"
+    } else {
+        "Show Raw Text"
+    })
+    .on_press(ChatViewMessage::ToggleRawText)
+    .into();
+
+    let members_btn: Element<ChatViewMessage> =
+        button(text(format!("Members ({})", app_state.roster.len())))
+            .on_press(ChatViewMessage::ToggleMembers)
+            .into();
+
+    let room_title: Element<ChatViewMessage> =
+        text(title).size(font_size + 3).font(cm_name_font).into();
+
+    let mut content = column![room_title];
+    if let Some(banner) = status_banner(&app_state.connection_status, font_size) {
+        content = content.push(banner);
+    }
+    let mut toolbar = row![];
+    if app_state.direct_view.is_some() {
+        toolbar = toolbar.push(button("Back to Room").on_press(ChatViewMessage::CloseDirect));
+    }
+    // who is typing is about the room
+    let typing = match app_state.direct_view {
+        Some(_) => String::new(),
+        None => typing_summary(&app_state.typing),
+    };
+    let mut input_area = column![text(typing)
+        .size(font_size - 4)
+        .color(Color::from_rgb(0.5, 0.5, 0.5))];
+    if let Some(compose_bar) = compose_bar {
+        input_area = input_area.push(compose_bar);
+    }
+    let content: Element<ChatViewMessage> = content
+        .push(
+            toolbar
+                .push(voice_controls)
+                .push(disconnect_btn)
+                .push(search_btn)
+                .push(raw_btn)
+                .push(members_btn)
+                .spacing(10),
+        )
+        .push(scrollable_messages)
+        .push(
+            input_area
+                .push(input_row)
+                .spacing(5)
+                .padding(10)
+                .width(Length::Fill),
+        )
+        .spacing(10)
+        .height(Length::Fill)
+        .padding(20)
+        .into();
+    let mut screen = row![content];
+    if let Some(thread_panel) = thread_panel {
+        screen = screen.push(thread_panel);
+    }
+    if let Some(search_panel) = search_panel {
+        screen = screen.push(search_panel);
+    }
+    if app_state.show_members {
+        screen = screen.push(members_panel_view(app_state, font_size));
+    }
+    screen.into()
+}
+
+// one element per message; names of other members open a direct thread with them,
+// quotes of replies open their thread
+fn conversation_view<'a>(
+    app_state: &'a ChatViewState,
+    messages: &[ConversationMessage],
+    selected: Option<usize>,
+    font_size: u16,
+) -> Vec<Element<'a, ChatViewMessage>> {
+    let mut cm_name_font = Font::with_name("clash-grotesk-variable");
+    cm_name_font.weight = font::Weight::Bold;
+    let mut cm_emote = Font::DEFAULT;
+    cm_emote.style = font::Style::Italic;
+
+    let cmm = &app_state.conversation_message_manager;
+    let mut reply_counts: HashMap<&str, usize> = HashMap::new();
+    for cm in messages {
+        if let Some(parent) = &cm.reply_to {
+            *reply_counts.entry(parent.as_str()).or_default() += 1;
+        }
+    }
+    let small_button = |label: String, message: ChatViewMessage| {
+        button(text(label).size(font_size - 4))
+            .on_press(message)
+            .style(button::text)
+            .padding(0)
+    };
+    messages
+        .iter()
+        .enumerate()
+        .map(|(position, msg)| {
+            let color = cmm.color_of(msg);
+            let message_element: Element<ChatViewMessage> = if msg.kind == MessageKind::Chat {
+                let mut message_column = column![];
+                let previous = position.checked_sub(1).and_then(|p| messages.get(p));
+                if !previous.is_some_and(|previous| same_group(previous, msg)) {
+                    let author = if msg.is_own() {
+                        "You"
+                    } else {
+                        msg.sender_name.trim()
+                    };
+                    let header = format!("{} · {}", author, timestamp::format_time(msg.time()));
+                    let header = text(header)
+                        .color(color)
+                        .size(font_size)
+                        .font(cm_name_font)
+                        .line_height(0.6);
+                    message_column = if msg.is_own() || app_state.direct_view.is_some() {
+                        message_column.push(header)
+                    } else {
+                        message_column.push(
+                            button(header)
+                                .on_press(ChatViewMessage::OpenDirect(msg.sender_name.clone()))
+                                .style(button::text)
+                                .padding(0),
+                        )
+                    };
+                }
+                if let Some(parent_id) = &msg.reply_to {
+                    let quote = match messages.iter().find(|cm| cm.id == *parent_id) {
+                        Some(parent) => format!("↪ {}", preview(parent)),
+                        None => String::from("↪ a message that is not shown here"),
+                    };
+                    message_column = message_column.push(
+                        button(
+                            text(quote)
+                                .size(font_size - 3)
+                                .color(Color::from_rgb(0.5, 0.5, 0.5)),
+                        )
+                        .on_press(ChatViewMessage::OpenThread(msg.id.clone()))
+                        .style(button::text)
+                        .padding(0),
+                    );
+                }
+                let blocks = app_state
+                    .formatted
+                    .get(&msg.id)
+                    .filter(|_| !app_state.show_raw);
+                let content: Element<ChatViewMessage> = match commands::emote(&msg.content) {
+                    // a tombstone where the message was
+                    _ if msg.is_deleted() => text("message deleted")
+                        .font(cm_emote)
+                        .color(Color::from_rgb(0.5, 0.5, 0.5))
+                        .size(font_size + 1)
+                        .into(),
+                    Some(action) => text(format!("* {} {}", msg.sender_name.trim(), action.trim()))
+                        .font(cm_emote)
+                        .color(color)
+                        .size(font_size + 1)
+                        .into(),
+                    None => match blocks {
+                        Some(blocks) => formatted_view(blocks, color, font_size + 1),
+                        None => text(msg.content.trim().to_string())
+                            .color(color)
+                            .size(font_size + 1)
+                            .into(),
+                    },
+                };
+                message_column = message_column.push(content);
+                match msg.delivery {
+                    DeliveryState::Pending => {
+                        let status = if app_state.outbox.contains(&msg.id) {
+                            "queued until reconnected"
+                        } else {
+                            "sending…"
+                        };
+                        message_column = message_column.push(
+                            text(status)
+                                .color(Color::from_rgb(0.5, 0.5, 0.5))
+                                .size(font_size - 4),
+                        );
+                    }
+                    DeliveryState::Failed => {
+                        let retry_btn = small_button(
+                            String::from("Retry"),
+                            ChatViewMessage::RetryMessage(msg.id.clone()),
+                        );
+                        message_column = message_column.push(
+                            row![
+                                text("not sent")
+                                    .color(Color::from_rgb(0.8, 0.1, 0.1))
+                                    .size(font_size - 4),
+                                retry_btn
+                            ]
+                            .spacing(10)
+                            .align_y(Alignment::Center),
+                        );
+                    }
+                    DeliveryState::Sent | DeliveryState::Received => {}
+                }
+                let reactions = app_state
+                    .reactions
+                    .get(&msg.id)
+                    .filter(|_| !msg.is_deleted());
+                if let Some(reactions) = reactions {
+                    let chips = reactions
+                        .iter()
+                        .map(|(emoji, names)| {
+                            reaction_chip(app_state, &msg.id, emoji, names, font_size)
+                        })
+                        .collect::<Vec<Element<ChatViewMessage>>>();
+                    message_column = message_column.push(Row::from_vec(chips).spacing(5));
+                }
+                let mut actions = row![].spacing(10);
+                if msg.revision == Revision::Edited {
+                    actions = actions.push(
+                        text("(edited)")
+                            .color(Color::from_rgb(0.5, 0.5, 0.5))
+                            .size(font_size - 4),
+                    );
+                }
+                if !msg.is_deleted() {
+                    actions = actions
+                        .push(small_button(
+                            String::from("Reply"),
+                            ChatViewMessage::ReplyTo(msg.id.clone()),
+                        ))
+                        .push(small_button(
+                            String::from("React"),
+                            ChatViewMessage::ShowReactionPicker(msg.id.clone()),
+                        ));
+                    // once the server has it, so members get the change after the message
+                    if msg.is_own() && msg.delivery == DeliveryState::Sent {
+                        actions = actions
+                            .push(small_button(
+                                String::from("Edit"),
+                                ChatViewMessage::EditMessage(msg.id.clone()),
+                            ))
+                            .push(small_button(
+                                String::from("Delete"),
+                                ChatViewMessage::DeleteMessage(msg.id.clone()),
+                            ));
+                    }
+                }
+                if let Some(count) = reply_counts.get(msg.id.as_str()) {
+                    let label = match count {
+                        1 => String::from("1 reply"),
+                        n => format!("{} replies", n),
+                    };
+                    actions = actions.push(small_button(
+                        label,
+                        ChatViewMessage::OpenThread(msg.id.clone()),
+                    ));
+                }
+                message_column = message_column.push(actions);
+                if app_state.reaction_picker.as_ref() == Some(&msg.id) {
+                    let picker = REACTION_PICKER
+                        .iter()
+                        .map(|emoji| {
+                            button(text(*emoji).size(font_size + 1))
+                                .on_press(ChatViewMessage::ToggleReaction(
+                                    msg.id.clone(),
+                                    emoji.to_string(),
+                                ))
+                                .style(button::text)
+                                .padding(2)
+                                .into()
+                        })
+                        .collect::<Vec<Element<ChatViewMessage>>>();
+                    message_column = message_column.push(Row::from_vec(picker).spacing(5));
+                }
+
+                let message_column = message_column.width(Length::Fill).padding(Padding {
+                    top: 0.0,
+                    right: 20.0,
+                    bottom: 0.0,
+                    left: 20.0,
+                });
+                if msg.is_own() {
+                    message_column.align_x(Alignment::End).into()
+                } else {
+                    message_column.into()
+                }
+            } else {
+                let generic_text: Element<ChatViewMessage> = text(format!(
+                    "{}  {}",
+                    timestamp::format_time(msg.time()),
+                    msg.content
+                ))
+                .color(color)
+                .size(font_size - 2)
+                .into();
+                row![generic_text].into()
+            };
+            // the id lets a search hit scroll to its row
+            let row = container(message_element).id(message_row_id(&msg.id));
+            if selected == Some(position) {
+                row.style(container::rounded_box).padding(5).into()
+            } else {
+                row.into()
+            }
+        })
+        .collect()
+}
+
+// a formatted message; quotes are set off in a box and code blocks are monospaced
+fn formatted_view(blocks: &[Block], color: Color, size: u16) -> Element<'_, ChatViewMessage> {
+    let style = markdown::Style::from_palette(super::THEME.palette());
+    let blocks = blocks
+        .iter()
+        .map(|block| {
+            let view = markdown::view(
+                &block.items,
+                markdown::Settings::with_text_size(size),
+                style,
+            )
+            .map(ChatViewMessage::OpenLink);
+            if block.quoted {
+                container(view)
+                    .padding([4, 10])
+                    .style(container::rounded_box)
+                    .into()
+            } else {
+                view
+            }
+        })
+        .collect::<Vec<Element<ChatViewMessage>>>();
+    container(Column::from_vec(blocks).spacing(5))
+        .style(move |_| container::Style::default().color(color))
+        .into()
+}
+
+// one emoji under a message with how many reacted with it, and who on hover;
+// highlighted when we are one of them, pressing it adds or takes back ours
+fn reaction_chip<'a>(
+    app_state: &ChatViewState,
+    id: &str,
+    emoji: &str,
+    names: &BTreeSet<String>,
+    font_size: u16,
+) -> Element<'a, ChatViewMessage> {
+    let who = names
+        .iter()
+        .map(|name| {
+            if *name == app_state.name {
+                "You"
+            } else {
+                name.as_str()
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let style = if names.contains(&app_state.name) {
+        button::primary
+    } else {
+        button::secondary
+    };
+    let chip = button(text(format!("{} {}", emoji, names.len())).size(font_size - 3))
+        .on_press(ChatViewMessage::ToggleReaction(
+            id.to_string(),
+            emoji.to_string(),
+        ))
+        .style(style)
+        .padding([2, 8]);
+    tooltip(
+        chip,
+        container(text(who).size(font_size - 4))
+            .padding(5)
+            .style(container::rounded_box),
+        tooltip::Position::Top,
+    )
+    .into()
+}
+
+// a message and every reply to it, next to the conversation
+fn thread_panel_view<'a>(
+    app_state: &'a ChatViewState,
+    messages: &[ConversationMessage],
+    root: &str,
+    font_size: u16,
+) -> Element<'a, ChatViewMessage> {
+    let thread = message::thread(messages, root);
+    let body: Element<ChatViewMessage> = if thread.is_empty() {
+        text("This message is no longer here")
+            .size(font_size - 2)
+            .into()
+    } else {
+        Column::from_vec(conversation_view(app_state, &thread, None, font_size))
+            .spacing(20)
+            .into()
+    };
+    column![
+        row![
+            text("Thread").size(font_size + 1).width(Length::Fill),
+            button("Close").on_press(ChatViewMessage::CloseThread)
+        ]
+        .align_y(Alignment::Center),
+        scrollable(body).height(Length::Fill)
+    ]
+    .spacing(10)
+    .padding(20)
+    .width(320)
+    .height(Length::Fill)
+    .into()
+}
+
+// above the input while we write a reply or fix one of our messages
+fn compose_bar_view<'a>(
+    app_state: &ChatViewState,
+    messages: &[ConversationMessage],
+    font_size: u16,
+) -> Option<Element<'a, ChatViewMessage>> {
+    let (label, cancel) = if app_state.editing.is_some() {
+        (
+            String::from("Editing your message"),
+            ChatViewMessage::CancelEdit,
+        )
+    } else {
+        let id = app_state.replying_to.as_ref()?;
+        let label = match messages.iter().find(|cm| cm.id == *id) {
+            Some(cm) => format!("Replying to {}", preview(cm)),
+            None => String::from("Replying to a message"),
+        };
+        (label, ChatViewMessage::CancelReply)
+    };
+    let bar = row![
+        text(label)
+            .size(font_size - 3)
+            .color(Color::from_rgb(0.5, 0.5, 0.5))
+            .width(Length::Fill),
+        button(text("Cancel").size(font_size - 4))
+            .on_press(cancel)
+            .style(button::text)
+            .padding(0)
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center);
+    Some(bar.into())
+}
+
+// a message on one short line, for reply quotes
+fn preview(cm: &ConversationMessage) -> String {
+    let author = if cm.is_own() {
+        "You"
+    } else {
+        cm.sender_name.trim()
+    };
+    let content = match commands::emote(&cm.content) {
+        _ if cm.is_deleted() => String::from("message deleted"),
+        Some(action) => format!("* {} {}", cm.sender_name.trim(), action.trim()),
+        None => cm.content.trim().replace('\n', " "),
+    };
+    format!("{}: {}", author, search::snippet(&content, "", 60))
+}
+
+fn members_panel_view(app_state: &ChatViewState, font_size: u16) -> Element<'_, ChatViewMessage> {
+    let cmm = &app_state.conversation_message_manager;
+    let members = app_state
+        .roster
+        .iter()
+        .map(|(name, presence)| {
+            let (status, status_color) = match presence {
+                Presence::Online => ("online", Color::from_rgb(0.2, 0.6, 0.3)),
+                Presence::Idle => ("idle", Color::from_rgb(0.7, 0.5, 0.2)),
+                Presence::InVoice => ("in voice", Color::from_rgb(0.2, 0.4, 0.7)),
+            };
+            let label = if name == app_state.name {
+                format!("{} (you)", name)
+            } else {
+                name.to_string()
+            };
+            let label = text(label)
+                .size(font_size - 1)
+                .color(cmm.color_of_key(&app_state.member_key(name)));
+            let label: Element<ChatViewMessage> = if name == app_state.name {
+                label.into()
+            } else {
+                button(label)
+                    .on_press(ChatViewMessage::OpenDirect(name.to_string()))
+                    .style(button::text)
+                    .padding(0)
+                    .into()
+            };
+            row![
+                container(label).width(Length::Fill),
+                text(status).size(font_size - 4).color(status_color)
+            ]
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .into()
+        })
+        .collect::<Vec<Element<ChatViewMessage>>>();
+
     column![
-        join_voice_btn,
-        disconnect_btn,
-        scrollable_messages,
-        container(input_row).padding(10).width(Length::Fill)
+        text("Members").size(font_size + 1),
+        scrollable(Column::from_vec(members).spacing(8)).height(Length::Fill)
     ]
     .spacing(10)
+    .padding(20)
+    .width(220)
     .height(Length::Fill)
+    .into()
+}
+
+fn search_panel_view<'a>(
+    search_panel: &'a SearchPanel,
+    messages: &[ConversationMessage],
+    cmm: &ConversationMessageManager,
+    font_size: u16,
+) -> Element<'a, ChatViewMessage> {
+    let text_ip: Element<ChatViewMessage> = text_input("Search messages", &search_panel.text)
+        .on_input(ChatViewMessage::SearchTextChanged)
+        .padding(10)
+        .size(16)
+        .into();
+    let sender_ip: Element<ChatViewMessage> = text_input("From sender", &search_panel.sender)
+        .on_input(ChatViewMessage::SearchSenderChanged)
+        .padding(10)
+        .size(16)
+        .into();
+    let from_ip: Element<ChatViewMessage> =
+        text_input("On or after YYYY-MM-DD", &search_panel.from)
+            .on_input(ChatViewMessage::SearchFromChanged)
+            .padding(10)
+            .size(16)
+            .into();
+    let to_ip: Element<ChatViewMessage> = text_input("On or before YYYY-MM-DD", &search_panel.to)
+        .on_input(ChatViewMessage::SearchToChanged)
+        .padding(10)
+        .size(16)
+        .into();
+
+    let summary = match &search_panel.error {
+        Some(error) => error.clone(),
+        None if search_panel.hits.len() >= search::MAX_HITS => {
+            format!("Showing the newest {} matches", search::MAX_HITS)
+        }
+        None => format!("{} matches", search_panel.hits.len()),
+    };
+    let summary_text: Element<ChatViewMessage> = text(summary).size(font_size - 2).into();
+
+    let hits = search_panel
+        .hits
+        .iter()
+        .filter_map(|hit| {
+            let msg = messages.get(hit.position)?;
+            let header = format!(
+                "{} · {}",
+                msg.sender_name,
+                timestamp::format_date_time(hit.timestamp)
+            );
+            let mut hit_column = column![text(header).size(font_size - 3).color(cmm.color_of(msg))];
+            // the message before the hit, for context
+            if let Some(previous) = hit.position.checked_sub(1).and_then(|p| messages.get(p)) {
+                hit_column = hit_column.push(
+                    text(search::snippet(&previous.content, "", 40))
+                        .size(font_size - 4)
+                        .color(Color::from_rgb(0.5, 0.5, 0.5)),
+                );
+            }
+            hit_column = hit_column.push(
+                text(search::snippet(&msg.content, &search_panel.text, 60)).size(font_size - 2),
+            );
+            let style = if search_panel.selected == Some(hit.position) {
+                button::primary
+            } else {
+                button::secondary
+            };
+            Some(
+                button(hit_column)
+                    .on_press(ChatViewMessage::SelectSearchHit(hit.position))
+                    .width(Length::Fill)
+                    .style(style)
+                    .into(),
+            )
+        })
+        .collect::<Vec<Element<ChatViewMessage>>>();
+
+    column![
+        text_ip,
+        sender_ip,
+        row![from_ip, to_ip].spacing(5),
+        summary_text,
+        scrollable(Column::from_vec(hits).spacing(5)).height(Length::Fill)
+    ]
+    .spacing(10)
     .padding(20)
+    .width(320)
+    .height(Length::Fill)
     .into()
 }
 
-pub fn recv_updates() -> impl Stream<Item = ChatViewMessage> {
+// empty while nobody is typing, so the line keeps its place
+fn typing_summary(typing: &BTreeMap<String, Instant>) -> String {
+    let names: Vec<&str> = typing.keys().map(String::as_str).collect();
+    match names.as_slice() {
+        [] => String::new(),
+        [name] => format!("{} is typing…", name),
+        [first, second] => format!("{} and {} are typing…", first, second),
+        _ => String::from("Several people are typing…"),
+    }
+}
+
+// consecutive messages by one author a few minutes apart share a header
+fn same_group(previous: &ConversationMessage, msg: &ConversationMessage) -> bool {
+    previous.kind == MessageKind::Chat
+        && previous.is_own() == msg.is_own()
+        && color_key(previous) == color_key(msg)
+        && msg.time().saturating_sub(previous.time()) <= GROUP_WINDOW_SECS
+}
+
+fn status_banner(
+    status: &ConnectionStatus,
+    font_size: u16,
+) -> Option<Element<'_, ChatViewMessage>> {
+    let warning = Color::new(0.8, 0.3, 0.3, 1.0);
+    match status {
+        ConnectionStatus::Connected => None,
+        ConnectionStatus::Reconnecting { attempt, retry_in } => Some(
+            text(format!(
+                "Connection lost, reconnecting in {:.1}s (attempt {} of {})…",
+                retry_in.as_secs_f32(),
+                attempt,
+                session::MAX_RECONNECT_ATTEMPTS
+            ))
+            .color(warning)
+            .size(font_size)
+            .into(),
+        ),
+        ConnectionStatus::Offline(reason) => {
+            let offline_text: Element<ChatViewMessage> = text(format!("Offline: {}", reason))
+                .color(warning)
+                .size(font_size)
+                .into();
+            let retry_btn: Element<ChatViewMessage> = button("Reconnect")
+                .on_press(ChatViewMessage::Reconnect)
+                .into();
+            Some(
+                row![offline_text, retry_btn]
+                    .spacing(10)
+                    .align_y(Alignment::Center)
+                    .into(),
+            )
+        }
+    }
+}
+
+pub fn subscription(app_state: &ChatViewState) -> Subscription<ChatViewMessage> {
+    let id = (
+        app_state.server_address.to_string(),
+        app_state.room_id.clone(),
+        app_state.session_id,
+    );
+    let mut subscriptions = vec![
+        Subscription::run_with_id(id, recv_updates()),
+        iced::time::every(PRESENCE_CHECK).map(|_| ChatViewMessage::PresenceTick),
+    ];
+    // only the room on screen completes on tab
+    if app_state.active {
+        subscriptions.push(iced::keyboard::on_key_press(|key, _| match key {
+            Key::Named(key::Named::Tab) => Some(ChatViewMessage::CompleteInput),
+            _ => None,
+        }));
+    }
+    if !app_state.typing.is_empty() {
+        subscriptions
+            .push(iced::time::every(Duration::from_secs(1)).map(|_| ChatViewMessage::TypingTick));
+    }
+    Subscription::batch(subscriptions)
+}
+
+// ends when iced drops the subscription or the session task drops its sender
+fn recv_updates() -> impl Stream<Item = ChatViewMessage> {
     stream::channel(100, |mut op| async move {
         let (sx, mut rx) = iced::futures::channel::mpsc::channel(100);
-        op.send(ChatViewMessage::StartReader(sx.clone()))
-            .await
-            .unwrap();
-        loop {
-            if sx.is_closed() || rx.is_terminated() {
+        if op.send(ChatViewMessage::StartReader(sx)).await.is_err() {
+            return;
+        }
+        while let Some(event) = rx.next().await {
+            if op
+                .send(ChatViewMessage::ReceivedMessage(event))
+                .await
+                .is_err()
+            {
                 break;
             }
-            let message = rx.select_next_some().await;
-            op.send(ChatViewMessage::ReceivedMessage(message))
-                .await
-                .unwrap();
         }
     })
 }
 
-struct ConversationMessage {
-    name: String,
-    color: Color,
-    content: String,
-}
-
 struct ConversationMessageManager {
     colors_list: Vec<Color>,
     current_index: usize,
@@ -320,50 +2630,112 @@ impl ConversationMessageManager {
         }
     }
 
-    fn format_conversation_message(
-        &mut self,
-        name: String,
-        content: String,
-    ) -> ConversationMessage {
-        let color = match self.color_user_map.get(&name) {
-            Some(c) => c.clone(),
-            None => {
-                self.color_user_map.insert(
-                    name.clone(),
-                    self.colors_list.get(self.current_index).unwrap().clone(),
-                );
-                let color = self.colors_list.get(self.current_index).unwrap();
-                self.current_index = (self.current_index + 1) % 20;
-                color.clone()
-            }
-        };
-        ConversationMessage {
-            name,
-            content,
-            color,
+    // gives every new member the next color in the list
+    fn assign_color(&mut self, key: &str) {
+        if !self.color_user_map.contains_key(key) {
+            let color = self.colors_list[self.current_index];
+            self.color_user_map.insert(key.to_string(), color);
+            self.current_index = (self.current_index + 1) % self.colors_list.len();
         }
     }
 
-    fn cm_from_string(&mut self, mut msg: String) -> ConversationMessage {
-        msg = msg.split(" ").collect::<Vec<_>>()[1..].join(" ");
-        if msg.contains(">") {
-            let split_msg = msg.split(">").collect::<Vec<&str>>();
-            let name = split_msg[0].trim().to_string();
-            let message = split_msg[1..].join("").to_string();
-            let cm = self.format_conversation_message(name, message);
-            cm
-        } else {
-            ConversationMessage {
-                name: String::new(),
-                color: Color::BLACK,
-                content: msg.clone(),
+    fn color_of_key(&self, key: &str) -> Color {
+        self.color_user_map
+            .get(key)
+            .copied()
+            .unwrap_or(Color::BLACK)
+    }
+
+    fn color_of(&self, cm: &ConversationMessage) -> Color {
+        match cm.kind {
+            MessageKind::Chat => self.color_of_key(&color_key(cm)),
+            MessageKind::Error => Color::from_rgb(0.8, 0.1, 0.1),
+            MessageKind::Join | MessageKind::Leave | MessageKind::Voice => {
+                Color::from_rgb(0.5, 0.5, 0.5)
             }
+            MessageKind::System => Color::BLACK,
         }
     }
+}
+
+// members are told apart by key where we know it, so two people with the same name
+// get different colors
+fn color_key(cm: &ConversationMessage) -> String {
+    cm.sender_id
+        .clone()
+        .unwrap_or_else(|| cm.sender_name.clone())
+}
+
+// rooms without a group session carry "name > text", with no id or time of their own
+fn legacy_message(plaintext: String) -> ConversationMessage {
+    match plaintext.split_once('>') {
+        Some((name, content)) => ConversationMessage {
+            sender_name: name.trim().to_string(),
+            content: content.trim_start().to_string(),
+            ..ConversationMessage::notice(MessageKind::Chat, String::new())
+        },
+        None => ConversationMessage::notice(MessageKind::System, plaintext),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iced::futures::channel::mpsc;
+    use tokio::time::timeout;
 
-    fn cms_from_vec(&mut self, v: Vec<String>) -> Vec<ConversationMessage> {
-        v.iter()
-            .map(|msg| self.cm_from_string(msg.clone()))
-            .collect::<Vec<ConversationMessage>>()
+    use super::*;
+    use crate::app::group::tests::{join, start_relay};
+    use crate::app::security::{self, IdentityKeyPair};
+
+    // like the real server, the stand-in relays our frames to the other members only,
+    // so nothing comes back to confirm them
+    #[tokio::test]
+    async fn sent_messages_are_marked_sent_and_saved() {
+        let server_address = start_relay().await;
+        let connection = join(&server_address, "alice").await;
+        let dir = std::env::temp_dir().join(format!("letschat-chat-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = HistoryStore::unlock(&dir, "local secret", 30).unwrap();
+        let account = Account {
+            server_address: server_address.clone(),
+            name: "alice".to_string(),
+            rooms: vec![],
+            identity: IdentityKeyPair::generate(),
+            history: Some(history.clone()),
+        };
+        let mut chat = ChatViewState::new(
+            vec![],
+            account,
+            "1".to_string(),
+            security::derive_room_key("passphrase", "1"),
+            connection,
+            false,
+            AudioBackend::Device,
+        );
+
+        let (sx, mut events) = mpsc::channel(100);
+        update(&mut chat, ChatViewMessage::StartReader(sx));
+        update(&mut chat, ChatViewMessage::SendMessage("hello".to_string()));
+        let delivery = |chat: &ChatViewState| {
+            let messages = chat.messages.lock().unwrap();
+            let sent = messages.iter().find(|cm| cm.content == "hello").unwrap();
+            sent.delivery
+        };
+        while delivery(&chat) == DeliveryState::Pending {
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .expect("the message was never handed to the server")
+                .expect("session ended");
+            update(&mut chat, ChatViewMessage::ReceivedMessage(event));
+        }
+        assert_eq!(delivery(&chat), DeliveryState::Sent);
+
+        let (_, saved) = history
+            .open_room(&server_address.to_string(), "1", "alice")
+            .unwrap();
+        assert!(saved
+            .iter()
+            .any(|cm| cm.content == "hello" && cm.delivery == DeliveryState::Sent));
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
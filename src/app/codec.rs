@@ -0,0 +1,138 @@
+use std::fmt;
+
+// every frame on the wire is a 4 byte big-endian payload length followed by the payload
+const HEADER_LEN: usize = 4;
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    FrameTooLarge(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge(len) => write!(
+                f,
+                "frame of {} bytes exceeds the {} byte limit",
+                len, MAX_FRAME_LEN
+            ),
+        }
+    }
+}
+
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// collects bytes from as many reads as it takes and hands back whole frames
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // true while part of a frame is still waiting for the rest of its bytes
+    pub fn has_pending_bytes(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, CodecError> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&self.buf[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(CodecError::FrameTooLarge(len));
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let frame = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn decodes_frame_split_across_every_byte() {
+        let wire = encode(b"NORMAL_MESSAGE aGVsbG8=");
+        let mut decoder = FrameDecoder::new();
+        for (i, byte) in wire.iter().enumerate() {
+            assert_eq!(
+                decoder.next_frame().unwrap(),
+                None,
+                "frame ready after {} bytes",
+                i
+            );
+            decoder.push(&[*byte]);
+        }
+        assert_eq!(
+            decode_all(&mut decoder),
+            vec![b"NORMAL_MESSAGE aGVsbG8=".to_vec()]
+        );
+    }
+
+    #[test]
+    fn splits_coalesced_frames() {
+        let mut wire = encode(b"first");
+        wire.extend(encode(b""));
+        wire.extend(encode(b"third frame"));
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&wire);
+        assert_eq!(
+            decode_all(&mut decoder),
+            vec![b"first".to_vec(), vec![], b"third frame".to_vec()]
+        );
+    }
+
+    #[test]
+    fn handles_arbitrary_chunk_boundaries() {
+        let payloads: Vec<Vec<u8>> = (0..20).map(|i| vec![b'x'; i * 300]).collect();
+        let wire: Vec<u8> = payloads.iter().flat_map(|p| encode(p)).collect();
+        for chunk_size in [1, 3, 7, 1024, 5000] {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = vec![];
+            for chunk in wire.chunks(chunk_size) {
+                decoder.push(chunk);
+                frames.extend(decode_all(&mut decoder));
+            }
+            assert_eq!(frames, payloads, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes());
+        assert_eq!(
+            decoder.next_frame(),
+            Err(CodecError::FrameTooLarge(MAX_FRAME_LEN + 1))
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+
+// what a line starting with "/" asks for; "//" escapes a message that really starts
+// with a slash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Text(String),
+    Command(Command),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Nick(String),
+    Join(String),
+    Leave,
+    Me(String),
+    Msg { to: String, text: String },
+    Clear,
+    Voice,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    // the usage line of the command
+    Usage(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => {
+                write!(f, "Unknown command /{}, /help lists them all", name)
+            }
+            CommandError::Usage(usage) => write!(f, "Usage: {}", usage),
+        }
+    }
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    // gets everything after the name, trimmed; None when it does not fit the usage
+    parse: fn(&str) -> Option<Command>,
+}
+
+// every command lives here: parsing, /help and completion all read this table
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "nick",
+        usage: "/nick <name>",
+        summary: "rejoin the room under another name",
+        parse: |args| one_word(args).map(Command::Nick),
+    },
+    CommandSpec {
+        name: "join",
+        usage: "/join <room>",
+        summary: "join another room on this server",
+        parse: |args| one_word(args).map(Command::Join),
+    },
+    CommandSpec {
+        name: "leave",
+        usage: "/leave",
+        summary: "leave this room",
+        parse: |args| no_args(args, Command::Leave),
+    },
+    CommandSpec {
+        name: "me",
+        usage: "/me <action>",
+        summary: "say what you are doing",
+        parse: |args| (!args.is_empty()).then(|| Command::Me(args.to_string())),
+    },
+    CommandSpec {
+        name: "msg",
+        usage: "/msg <user> <message>",
+        summary: "send a message to one member only",
+        parse: |args| {
+            let (to, text) = args.split_once(char::is_whitespace)?;
+            let text = text.trim();
+            (!text.is_empty()).then(|| Command::Msg {
+                to: to.to_string(),
+                text: text.to_string(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "clear",
+        usage: "/clear",
+        summary: "clear the conversation on screen, saved history stays",
+        parse: |args| no_args(args, Command::Clear),
+    },
+    CommandSpec {
+        name: "voice",
+        usage: "/voice",
+        summary: "join or leave the voice channel",
+        parse: |args| no_args(args, Command::Voice),
+    },
+    CommandSpec {
+        name: "help",
+        usage: "/help",
+        summary: "list the commands",
+        parse: |args| no_args(args, Command::Help),
+    },
+];
+
+fn one_word(args: &str) -> Option<String> {
+    (!args.is_empty() && !args.contains(char::is_whitespace)).then(|| args.to_string())
+}
+
+fn no_args(args: &str, command: Command) -> Option<Command> {
+    args.is_empty().then_some(command)
+}
+
+pub fn parse(input: &str) -> Result<Input, CommandError> {
+    let input = input.trim();
+    let Some(line) = input.strip_prefix('/') else {
+        return Ok(Input::Text(input.to_string()));
+    };
+    if line.starts_with('/') {
+        return Ok(Input::Text(line.to_string()));
+    }
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let name = name.to_lowercase();
+    let spec = COMMANDS
+        .iter()
+        .find(|spec| spec.name == name)
+        .ok_or(CommandError::Unknown(name))?;
+    (spec.parse)(args.trim())
+        .map(Input::Command)
+        .ok_or(CommandError::Usage(spec.usage))
+}
+
+// "/me waves" goes to the room as it is, like irc clients do, so members without
+// this client still see something sensible
+pub fn emote_text(action: &str) -> String {
+    format!("/me {}", action)
+}
+
+// the action of a message sent with /me
+pub fn emote(content: &str) -> Option<&str> {
+    content.strip_prefix("/me ")
+}
+
+// true while the input is a command rather than a message to the room
+pub fn is_command(input: &str) -> bool {
+    let input = input.trim_start();
+    input.starts_with('/') && !input.starts_with("//")
+}
+
+// completes the command name being typed, or else the last word as a member name.
+// with several candidates it goes as far as they agree; None when there is nothing
+// to add
+pub fn complete<'a>(input: &str, members: impl Iterator<Item = &'a str>) -> Option<String> {
+    if let Some(partial) = input.strip_prefix('/') {
+        if !partial.is_empty() && !partial.contains(char::is_whitespace) {
+            let names = COMMANDS.iter().map(|spec| spec.name);
+            return extend(input, partial, names);
+        }
+    }
+    let start = input
+        .rfind(char::is_whitespace)
+        .map(|i| i + 1)
+        .unwrap_or_default();
+    let partial = &input[start..];
+    if partial.is_empty() || partial.starts_with('/') {
+        return None;
+    }
+    extend(input, partial, members)
+}
+
+// replaces the trailing `partial` with the longest completion all candidates share,
+// plus a space once only one is left
+fn extend<'a>(
+    input: &str,
+    partial: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Option<String> {
+    let partial_lower = partial.to_lowercase();
+    let matches: Vec<&str> = candidates
+        .filter(|candidate| candidate.to_lowercase().starts_with(&partial_lower))
+        .collect();
+    let first = matches.first()?;
+    let mut completion = first.to_string();
+    for candidate in &matches[1..] {
+        let shared = completion
+            .chars()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        completion.truncate(shared);
+    }
+    if matches.len() == 1 {
+        completion.push(' ');
+    }
+    if completion.to_lowercase() == partial_lower {
+        return None;
+    }
+    Some(format!(
+        "{}{}",
+        &input[..input.len() - partial.len()],
+        completion
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_and_plain_text() {
+        assert_eq!(parse(" hello "), Ok(Input::Text("hello".to_string())));
+        assert_eq!(parse("//shrug"), Ok(Input::Text("/shrug".to_string())));
+        assert_eq!(
+            parse("/NICK  bob"),
+            Ok(Input::Command(Command::Nick("bob".to_string())))
+        );
+        assert_eq!(
+            parse("/msg alice see you at 5"),
+            Ok(Input::Command(Command::Msg {
+                to: "alice".to_string(),
+                text: "see you at 5".to_string(),
+            }))
+        );
+        assert_eq!(
+            parse("/me waves"),
+            Ok(Input::Command(Command::Me("waves".to_string())))
+        );
+        assert_eq!(parse("/voice"), Ok(Input::Command(Command::Voice)));
+    }
+
+    #[test]
+    fn reports_unknown_commands_and_bad_usage() {
+        assert_eq!(
+            parse("/dance"),
+            Err(CommandError::Unknown("dance".to_string()))
+        );
+        let error = parse("/nick two words").unwrap_err();
+        assert_eq!(error.to_string(), "Usage: /nick <name>");
+        assert!(parse("/msg alice").is_err());
+        assert!(parse("/clear everything").is_err());
+    }
+
+    #[test]
+    fn completes_commands_and_members() {
+        let members = || ["alice", "Albert", "bob"].into_iter();
+        assert_eq!(complete("/he", members()), Some("/help ".to_string()));
+        assert_eq!(complete("/msg b", members()), Some("/msg bob ".to_string()));
+        assert_eq!(complete("hi ali", members()), Some("hi alice ".to_string()));
+        // several candidates: only as far as they agree
+        assert_eq!(complete("hi al", members()), None);
+        let members = || ["carol", "caroline"].into_iter();
+        assert_eq!(complete("ca", members()), Some("carol".to_string()));
+        assert_eq!(complete("/x", members()), None);
+        assert_eq!(complete("", members()), None);
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use toml_edit::DocumentMut;
+
+use super::voice::AudioBackend;
+
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 8000;
+
+const HOST_ENV: &str = "LETSCHAT_HOST";
+const PORT_ENV: &str = "LETSCHAT_PORT";
+const CONFIG_ENV: &str = "LETSCHAT_CONFIG";
+const DEFAULT_HISTORY_DAYS: u32 = 30;
+// next to the config file, readable by us only
+const IDENTITY_KEYS_FILE: &str = "identity-keys.toml";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerAddress {
+    fn default() -> Self {
+        ServerAddress {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl ServerAddress {
+    // accepts "host:port" or a bare "host", which uses the default port; ipv6
+    // addresses go in brackets, as in "[::1]:8000" or "[::1]"
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']')?;
+                let port = match rest {
+                    "" => None,
+                    rest => Some(rest.strip_prefix(':')?),
+                };
+                (host, port)
+            }
+            // a second colon means an ipv6 address without its brackets
+            None if s.matches(':').count() > 1 => return None,
+            None => match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            },
+        };
+        if host.is_empty() || host.contains(['[', ']']) {
+            return None;
+        }
+        let port = match port {
+            Some(port) => port.parse::<u16>().ok()?,
+            None => DEFAULT_PORT,
+        };
+        Some(ServerAddress {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    // later sources override earlier ones: defaults, config file, env vars, cli args
+    fn resolve(
+        args: CliArgs,
+        config_file: &ConfigFile,
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let mut server_address = ServerAddress::default();
+        server_address.apply(config_file.host.clone(), config_file.port);
+
+        let env_port = env_var(PORT_ENV).and_then(|p| p.parse::<u16>().ok());
+        server_address.apply(env_var(HOST_ENV), env_port);
+
+        if let Some(s) = args.server.as_deref().and_then(ServerAddress::parse) {
+            server_address = s;
+        }
+        server_address.apply(args.host, args.port);
+
+        server_address
+    }
+
+    fn apply(&mut self, host: Option<String>, port: Option<u16>) {
+        if let Some(host) = host.filter(|h| !h.trim().is_empty()) {
+            self.host = host.trim().to_string();
+        }
+        if let Some(port) = port {
+            self.port = port;
+        }
+    }
+}
+
+// a saved server and the account used on it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: String,
+    // rooms offered on the welcome form
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    // where identity keys used to be kept; read once to move them to the key file
+    #[serde(default, skip_serializing)]
+    pub identity_key: Option<String>,
+}
+
+impl Profile {
+    pub fn server_address(&self) -> ServerAddress {
+        ServerAddress {
+            host: self.host.clone(),
+            port: self.port,
+        }
+    }
+}
+
+pub struct Config {
+    // used when no profile is picked
+    pub server_address: ServerAddress,
+    pub profiles: Vec<Profile>,
+    // how long local history is kept, 0 keeps none
+    pub history_days: u32,
+    // whether members see when we are typing
+    pub send_typing: bool,
+    // the sound card, or raw audio files when both voice_input and voice_output are set
+    pub audio: AudioBackend,
+    // base64 x25519 secrets by profile name, so members see the same identity key
+    // across restarts
+    identity_keys: BTreeMap<String, String>,
+    path: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let args = CliArgs::parse(env::args().skip(1));
+        let path = ConfigFile::path(args.config.clone());
+        Config::read(args, path, |key| env::var(key).ok())
+    }
+
+    fn read(
+        args: CliArgs,
+        path: Option<PathBuf>,
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let config_file = path
+            .as_ref()
+            .and_then(|path| ConfigFile::load(path))
+            .unwrap_or_default();
+        let mut identity_keys = path
+            .as_deref()
+            .and_then(|path| load_identity_keys(&identity_keys_path(path)))
+            .unwrap_or_default();
+        let mut moved = false;
+        for profile in &config_file.profiles {
+            if let Some(key) = &profile.identity_key {
+                identity_keys
+                    .entry(profile.name.clone())
+                    .or_insert_with(|| key.clone());
+                moved = true;
+            }
+        }
+        let config = Config {
+            profiles: config_file.profiles.clone(),
+            history_days: config_file.history_days.unwrap_or(DEFAULT_HISTORY_DAYS),
+            send_typing: config_file.send_typing.unwrap_or(true),
+            audio: match (&config_file.voice_input, &config_file.voice_output) {
+                (Some(input), Some(output)) => AudioBackend::Files {
+                    input: input.clone(),
+                    output: output.clone(),
+                },
+                _ => AudioBackend::Device,
+            },
+            server_address: ServerAddress::resolve(args, &config_file, env_var),
+            identity_keys,
+            path,
+        };
+        // keys found in the config file are written out of it
+        if moved {
+            if let Err(e) = config.save_profiles() {
+                println!("Could not move identity keys out of the config file: {}", e);
+            }
+        }
+        config
+    }
+
+    pub fn identity_key(&self, profile_name: &str) -> Option<&str> {
+        self.identity_keys.get(profile_name).map(String::as_str)
+    }
+
+    // saved with the profiles
+    pub fn set_identity_key(&mut self, profile_name: &str, key: Option<String>) {
+        match key {
+            Some(key) => self.identity_keys.insert(profile_name.to_string(), key),
+            None => self.identity_keys.remove(profile_name),
+        };
+    }
+
+    // rewrites the profiles table in the config file, leaving the other settings and
+    // comments alone, and the identity keys of those profiles in their own file
+    pub fn save_profiles(&self) -> Result<(), String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| String::from("no config directory on this system"))?;
+        let mut document = match fs::read_to_string(path) {
+            // never clobber a file we could not make sense of
+            Ok(contents) => contents
+                .parse::<DocumentMut>()
+                .map_err(|e| format!("could not parse {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+        };
+        let profiles = ProfilesTable {
+            profiles: &self.profiles,
+        };
+        let profiles = toml::to_string(&profiles)
+            .map_err(|e| e.to_string())?
+            .parse::<DocumentMut>()
+            .map_err(|e| e.to_string())?;
+        match profiles.get("profiles") {
+            Some(item) => document.insert("profiles", item.clone()),
+            None => document.remove("profiles"),
+        };
+
+        let identity_keys = self
+            .profiles
+            .iter()
+            .filter_map(|profile| {
+                let key = self.identity_keys.get(&profile.name)?;
+                Some((profile.name.clone(), key.clone()))
+            })
+            .collect::<BTreeMap<String, String>>();
+        let identity_keys = toml::to_string(&identity_keys).map_err(|e| e.to_string())?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let keys_path = identity_keys_path(path);
+        write_private(&keys_path, identity_keys.as_bytes())
+            .map_err(|e| format!("could not write {}: {}", keys_path.display(), e))?;
+        fs::write(path, document.to_string())
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+#[derive(Serialize)]
+struct ProfilesTable<'a> {
+    profiles: &'a [Profile],
+}
+
+fn identity_keys_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(IDENTITY_KEYS_FILE)
+}
+
+fn load_identity_keys(path: &Path) -> Option<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(path).ok()?;
+    match toml::from_str(&contents) {
+        Ok(identity_keys) => Some(identity_keys),
+        Err(e) => {
+            println!("Could not parse {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// with permissions for the owner only, on systems that have them
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies to new files
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[derive(Default)]
+struct CliArgs {
+    server: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    config: Option<PathBuf>,
+}
+
+impl CliArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut cli_args = CliArgs::default();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next());
+            match flag.as_str() {
+                "--server" | "-s" => cli_args.server = value(),
+                "--host" => cli_args.host = value(),
+                "--port" | "-p" => cli_args.port = value().and_then(|p| p.parse().ok()),
+                "--config" | "-c" => cli_args.config = value().map(PathBuf::from),
+                _ => println!("Ignoring unknown argument {}", flag),
+            }
+        }
+        cli_args
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    host: Option<String>,
+    port: Option<u16>,
+    history_days: Option<u32>,
+    send_typing: Option<bool>,
+    voice_input: Option<PathBuf>,
+    voice_output: Option<PathBuf>,
+    #[serde(default)]
+    profiles: Vec<Profile>,
+}
+
+impl ConfigFile {
+    fn path(explicit: Option<PathBuf>) -> Option<PathBuf> {
+        explicit
+            .or_else(|| env::var(CONFIG_ENV).ok().map(PathBuf::from))
+            .or_else(|| dirs::config_dir().map(|d| d.join("letschat").join("config.toml")))
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        match toml::from_str(&contents) {
+            Ok(config_file) => Some(config_file),
+            Err(e) => {
+                println!("Could not parse {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(host: &str, port: u16) -> ServerAddress {
+        ServerAddress {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_hosts_ports_and_bracketed_ipv6() {
+        assert_eq!(
+            ServerAddress::parse(" example.org:9000 "),
+            Some(address("example.org", 9000))
+        );
+        assert_eq!(
+            ServerAddress::parse("example.org"),
+            Some(address("example.org", DEFAULT_PORT))
+        );
+        assert_eq!(
+            ServerAddress::parse("[::1]:9000"),
+            Some(address("::1", 9000))
+        );
+        assert_eq!(
+            ServerAddress::parse("[::1]"),
+            Some(address("::1", DEFAULT_PORT))
+        );
+        assert_eq!(address("::1", 9000).to_string(), "[::1]:9000");
+        for bad in [
+            "",
+            ":9000",
+            "host:",
+            "host:port",
+            "::1:9000",
+            "[::1]9000",
+            "[::1",
+        ] {
+            assert_eq!(ServerAddress::parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn cli_beats_env_beats_config_file() {
+        let config_file = ConfigFile {
+            host: Some("file.example".to_string()),
+            port: Some(7000),
+            ..ConfigFile::default()
+        };
+        let no_env = |_: &str| None;
+        let env = |key: &str| match key {
+            HOST_ENV => Some("env.example".to_string()),
+            PORT_ENV => Some("7100".to_string()),
+            _ => None,
+        };
+        let cli = |args: &[&str]| CliArgs::parse(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(
+            ServerAddress::resolve(cli(&[]), &ConfigFile::default(), no_env),
+            ServerAddress::default()
+        );
+        assert_eq!(
+            ServerAddress::resolve(cli(&[]), &config_file, no_env),
+            address("file.example", 7000)
+        );
+        assert_eq!(
+            ServerAddress::resolve(cli(&[]), &config_file, env),
+            address("env.example", 7100)
+        );
+        // each part is overridden on its own
+        assert_eq!(
+            ServerAddress::resolve(cli(&["--port", "7200"]), &config_file, env),
+            address("env.example", 7200)
+        );
+        assert_eq!(
+            ServerAddress::resolve(cli(&["--server=cli.example:7300"]), &config_file, env),
+            address("cli.example", 7300)
+        );
+        assert_eq!(
+            ServerAddress::resolve(
+                cli(&["-s", "cli.example:7300", "--host", "other.example"]),
+                &config_file,
+                env
+            ),
+            address("other.example", 7300)
+        );
+    }
+
+    #[test]
+    fn saves_profiles_without_touching_the_rest() {
+        let dir = std::env::temp_dir().join(format!("letschat-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "# my server\nhost = \"example.org\"\nfuture_setting = 1\n\n\
+             [[profiles]]\nname = \"work\"\nhost = \"example.org\"\nport = 8000\n\
+             identity_key = \"c2VjcmV0\"\n",
+        )
+        .unwrap();
+
+        // a key still in the config file moves to the key file
+        let mut config = Config::read(CliArgs::default(), Some(path.clone()), |_| None);
+        assert_eq!(config.identity_key("work"), Some("c2VjcmV0"));
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("c2VjcmV0"));
+        assert!(contents.starts_with("# my server\nhost = \"example.org\"\nfuture_setting = 1\n"));
+
+        config.profiles.push(Profile {
+            name: "home".to_string(),
+            host: "localhost".to_string(),
+            port: 9000,
+            ..Profile::default()
+        });
+        config.set_identity_key("home", Some("aG9tZQ==".to_string()));
+        config.save_profiles().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("# my server") && contents.contains("future_setting = 1"));
+        assert!(!contents.contains("aG9tZQ=="));
+
+        let config = Config::read(CliArgs::default(), Some(path), |_| None);
+        assert_eq!(config.profiles.len(), 2);
+        assert_eq!(config.identity_key("home"), Some("aG9tZQ=="));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let keys = fs::metadata(dir.join(IDENTITY_KEYS_FILE)).unwrap();
+            assert_eq!(keys.permissions().mode() & 0o777, 0o600);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
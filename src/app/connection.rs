@@ -0,0 +1,145 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::Mutex,
+    time::timeout,
+};
+
+use super::codec::{self, CodecError, FrameDecoder};
+use super::config::ServerAddress;
+use super::protocol::{ClientFrame, ProtocolError, ServerFrame};
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum ConnectionError {
+    Timeout,
+    Closed,
+    Codec(CodecError),
+    Protocol(ProtocolError),
+    Io(String),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Timeout => write!(f, "timed out"),
+            ConnectionError::Closed => write!(f, "server closed the connection"),
+            ConnectionError::Codec(e) => write!(f, "{}", e),
+            ConnectionError::Protocol(e) => write!(f, "{}", e),
+            ConnectionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e.to_string())
+    }
+}
+
+impl From<CodecError> for ConnectionError {
+    fn from(e: CodecError) -> Self {
+        ConnectionError::Codec(e)
+    }
+}
+
+impl From<ProtocolError> for ConnectionError {
+    fn from(e: ProtocolError) -> Self {
+        ConnectionError::Protocol(e)
+    }
+}
+
+#[derive(Debug)]
+struct Reader {
+    read_half: OwnedReadHalf,
+    decoder: FrameDecoder,
+}
+
+// cheap to clone so it can travel inside view messages; reading and writing lock
+// separate halves so a pending read never holds up a send
+#[derive(Debug, Clone)]
+pub struct Connection {
+    reader: Arc<Mutex<Reader>>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+}
+
+impl Connection {
+    fn new(tcp_stream: TcpStream) -> Self {
+        let (read_half, write_half) = tcp_stream.into_split();
+        Connection {
+            reader: Arc::new(Mutex::new(Reader {
+                read_half,
+                decoder: FrameDecoder::new(),
+            })),
+            writer: Arc::new(Mutex::new(write_half)),
+        }
+    }
+
+    // cancel safe: bytes are only consumed once they are in the decoder
+    async fn read_message(&self) -> Result<String, ConnectionError> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            if let Some(frame) = reader.decoder.next_frame()? {
+                return Ok(String::from_utf8_lossy(&frame).to_string());
+            }
+            let mut buf = [0u8; 1024];
+            let bytes_read = reader.read_half.read(&mut buf).await?;
+            if bytes_read == 0 {
+                return Err(ConnectionError::Closed);
+            }
+            reader.decoder.push(&buf[..bytes_read]);
+        }
+    }
+
+    pub async fn recv(&self) -> Result<ServerFrame, ConnectionError> {
+        Ok(ServerFrame::parse(&self.read_message().await?)?)
+    }
+
+    pub async fn send(&self, frame: &ClientFrame) -> Result<(), ConnectionError> {
+        self.write_message(&frame.serialize()).await
+    }
+
+    async fn write_message(&self, message: &str) -> Result<(), ConnectionError> {
+        self.writer
+            .lock()
+            .await
+            .write_all(&codec::encode(message.as_bytes()))
+            .await?;
+        Ok(())
+    }
+}
+
+// connects and waits for the server greeting
+pub async fn connect(
+    server_address: ServerAddress,
+) -> Result<(Connection, ServerFrame), ConnectionError> {
+    timeout(CONNECT_TIMEOUT, async {
+        let tcp_stream =
+            TcpStream::connect((server_address.host.as_str(), server_address.port)).await?;
+        let connection = Connection::new(tcp_stream);
+        let greeting = connection.recv().await?;
+        Ok((connection, greeting))
+    })
+    .await
+    .map_err(|_| ConnectionError::Timeout)?
+}
+
+// sends the JOIN_ROOM frame and returns the server's reply
+pub async fn join_room(
+    connection: Connection,
+    join_frame: ClientFrame,
+) -> Result<ServerFrame, ConnectionError> {
+    timeout(JOIN_TIMEOUT, async {
+        connection.send(&join_frame).await?;
+        connection.recv().await
+    })
+    .await
+    .map_err(|_| ConnectionError::Timeout)?
+}
@@ -0,0 +1,220 @@
+use iced::widget::markdown;
+
+// links come from other members, so only these are ever opened
+const LINK_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+// a run of a message's markup, quoted or not; iced's markdown has no block quotes,
+// so they are split out here and drawn around it
+pub struct Block {
+    pub quoted: bool,
+    pub items: Vec<markdown::Item>,
+}
+
+// None for messages without markup, which keep being shown as plain text
+pub fn parse(content: &str) -> Option<Vec<Block>> {
+    if !has_markup(content) {
+        return None;
+    }
+    let blocks = split_quotes(content)
+        .into_iter()
+        .map(|(quoted, source)| Block {
+            quoted,
+            items: markdown::parse(&restrict(&source)).collect(),
+        })
+        .collect();
+    Some(blocks)
+}
+
+// checked again when a link is clicked, whatever the markdown parser let through
+pub fn is_safe_link(url: &markdown::Url) -> bool {
+    LINK_SCHEMES.contains(&url.scheme())
+}
+
+fn has_markup(content: &str) -> bool {
+    content.contains(['*', '_', '`', '[', '~'])
+        || content.contains("http://")
+        || content.contains("https://")
+        || content.lines().any(|line| {
+            let line = line.trim_start();
+            line.starts_with('>')
+                || line.starts_with("- ")
+                || line.starts_with("+ ")
+                || line
+                    .split_once(". ")
+                    .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+// consecutive lines starting with ">" form a quote, without the marker; fenced code
+// is left alone
+fn split_quotes(content: &str) -> Vec<(bool, String)> {
+    let mut blocks: Vec<(bool, String)> = vec![];
+    let mut in_fence = false;
+    for line in content.lines() {
+        let quote = match line.trim_start().strip_prefix('>') {
+            Some(rest) if !in_fence => Some(rest.strip_prefix(' ').unwrap_or(rest)),
+            _ => None,
+        };
+        if quote.is_none() && is_fence(line) {
+            in_fence = !in_fence;
+        }
+        let (quoted, line) = match quote {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        match blocks.last_mut() {
+            Some((last_quoted, source)) if *last_quoted == quoted => {
+                source.push('\n');
+                source.push_str(line);
+            }
+            _ => blocks.push((quoted, line.to_string())),
+        }
+    }
+    blocks
+}
+
+// keeps markdown to what suits a chat message: headings, rules, tables and html are
+// shown as typed, every line break is kept and bare links become clickable
+fn restrict(source: &str) -> String {
+    let mut out = String::with_capacity(source.len() * 2);
+    let mut in_fence = false;
+    for (i, line) in source.lines().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if is_fence(line) {
+            in_fence = !in_fence;
+            out.push_str(line);
+            continue;
+        }
+        if in_fence {
+            out.push_str(line);
+            continue;
+        }
+        let text = line.trim_start();
+        out.push_str(&line[..line.len() - text.len()]);
+        // a heading, or a line that would make the one above a heading
+        let underline = !text.is_empty() && text.trim_end().chars().all(|c| "-=+".contains(c));
+        if text.starts_with('#') || underline {
+            out.push('\\');
+        }
+        restrict_inline(text, &mut out);
+        // a hard break; trailing spaces at the end of a paragraph are dropped
+        out.push_str("  ");
+    }
+    out
+}
+
+fn restrict_inline(text: &str, out: &mut String) {
+    let mut rest = text;
+    let mut after_space = true;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            // a code span is copied as it is, up to the run of backticks closing it
+            let run = rest.len() - rest.trim_start_matches('`').len();
+            let ticks = &rest[..run];
+            let closing = rest[run..]
+                .match_indices(ticks)
+                .find(|(at, _)| !rest[run + at + run..].starts_with('`'))
+                .map(|(at, _)| run + at + run);
+            let end = closing.unwrap_or(run);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            after_space = false;
+            continue;
+        }
+        if after_space && (rest.starts_with("http://") || rest.starts_with("https://")) {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let url = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+            if !url.contains(['<', '>']) {
+                out.push('<');
+                out.push_str(url);
+                out.push('>');
+                rest = &rest[url.len()..];
+                after_space = false;
+                continue;
+            }
+        }
+        if matches!(c, '<' | '|') {
+            out.push('\\');
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+        after_space = c.is_whitespace();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_messages_have_no_markup() {
+        assert!(parse("see you at 5, ok?").is_none());
+        assert!(parse("**soon**").is_some());
+        assert!(parse("1. first\n2. second").is_some());
+        assert!(parse("look https://example.com").is_some());
+    }
+
+    #[test]
+    fn opens_only_web_and_mail_links() {
+        let safe = |url: &str| is_safe_link(&markdown::Url::parse(url).unwrap());
+        assert!(safe("https://example.com/a?b=c"));
+        assert!(safe("HTTP://example.com"));
+        assert!(safe("mailto:bob@example.com"));
+        assert!(!safe("file:///etc/passwd"));
+        assert!(!safe("javascript:alert(1)"));
+        assert!(!safe("steam://run/440"));
+        assert!(!safe("smb://server/share"));
+    }
+
+    #[test]
+    fn splits_quotes_outside_code() {
+        assert_eq!(
+            split_quotes("> one\n>two\nthree\n```\n> not a quote\n```"),
+            vec![
+                (true, "one\ntwo".to_string()),
+                (false, "three\n```\n> not a quote\n```".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn restricts_markdown_to_the_chat_subset() {
+        assert_eq!(restrict("# title"), "\\# title  ");
+        assert_eq!(restrict("text\n---"), "text  \n\\---  ");
+        assert_eq!(restrict("a <b> | c"), "a \\<b> \\| c  ");
+        // code is shown exactly as typed
+        assert_eq!(
+            restrict("use `a<b` or `` `|` ``"),
+            "use `a<b` or `` `|` ``  "
+        );
+        assert_eq!(restrict("```\n# <x>\n```"), "```\n# <x>\n```");
+        assert_eq!(
+            restrict("see https://example.com/a_b. and [x](https://example.com)"),
+            "see <https://example.com/a_b>. and [x](https://example.com)  "
+        );
+    }
+
+    #[test]
+    fn highlights_fenced_code() {
+        let blocks = parse("```rust\nlet x = \"one\";\n```").unwrap();
+        let Some(markdown::Item::CodeBlock(code)) = blocks[0].items.first() else {
+            panic!("expected a code block");
+        };
+        let style = markdown::Style::from_palette(iced::Theme::Dark.palette());
+        let mut colors = vec![];
+        for span in code.spans(style).iter() {
+            if let Some(color) = span.color.filter(|color| !colors.contains(color)) {
+                colors.push(color);
+            }
+        }
+        // keyword, string and the rest each get their own
+        assert!(colors.len() > 1, "{:?}", colors);
+    }
+}
@@ -0,0 +1,591 @@
+use std::{collections::HashMap, fmt};
+
+use super::protocol::{
+    ClientFrame, DirectPayload, GroupPayload, KeyAnnouncement, SenderKeyDelivery,
+};
+use super::security::{self, DecryptError, IdentityKeyPair, RoomKey};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupError {
+    UnknownSender(String),
+    NoSenderKey(String),
+    KeyChanged(String),
+    Decrypt(DecryptError),
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::UnknownSender(name) => write!(f, "{} has not published a key", name),
+            GroupError::NoSenderKey(name) => write!(f, "no group key from {} yet", name),
+            GroupError::KeyChanged(name) => {
+                write!(
+                    f,
+                    "{} announced a different identity key, ignoring it",
+                    name
+                )
+            }
+            GroupError::Decrypt(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<DecryptError> for GroupError {
+    fn from(e: DecryptError) -> Self {
+        GroupError::Decrypt(e)
+    }
+}
+
+struct Peer {
+    public_key: [u8; 32],
+    sender_key: Option<[u8; 16]>,
+}
+
+// sender-keys group encryption: every member encrypts its messages with its own
+// random sender key and hands that key to each other member sealed under their
+// pairwise x25519 key, so the server only ever relays ciphertext
+pub struct GroupSession {
+    name: String,
+    room_key: RoomKey,
+    identity: IdentityKeyPair,
+    sender_key: [u8; 16],
+    // the one replaced when a member left, for our own messages still on their way
+    previous_sender_key: Option<[u8; 16]>,
+    peers: HashMap<String, Peer>,
+}
+
+impl GroupSession {
+    pub fn new(name: String, room_key: RoomKey, identity: IdentityKeyPair) -> Self {
+        GroupSession {
+            name,
+            room_key,
+            identity,
+            sender_key: security::generate_sender_key(),
+            previous_sender_key: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn identity(&self) -> &IdentityKeyPair {
+        &self.identity
+    }
+
+    pub fn fingerprint(&self) -> String {
+        security::fingerprint(&self.identity.public_key())
+    }
+
+    // None until the member has announced a key
+    pub fn peer_fingerprint(&self, name: &str) -> Option<String> {
+        self.peers
+            .get(name)
+            .map(|peer| security::fingerprint(&peer.public_key))
+    }
+
+    pub fn publish(&self) -> ClientFrame {
+        ClientFrame::PublicKey(KeyAnnouncement {
+            name: self.name.clone(),
+            public_key: self.identity.public_key(),
+        })
+    }
+
+    fn deliver_sender_key(&self, public_key: &[u8; 32]) -> ClientFrame {
+        let pairwise_key = self.identity.pairwise_key(public_key, &self.room_key);
+        ClientFrame::SenderKey(SenderKeyDelivery {
+            name: self.name.clone(),
+            recipient: *public_key,
+            sealed_key: security::seal(&self.sender_key, &pairwise_key),
+        })
+    }
+
+    // our sender key for every member we know, after we rejoined: members forget us
+    // when we leave, and learn our key again from the announcement sent before these
+    pub fn sender_key_deliveries(&self) -> Vec<ClientFrame> {
+        self.peers
+            .values()
+            .map(|peer| self.deliver_sender_key(&peer.public_key))
+            .collect()
+    }
+
+    // returns the frames to send back. an announcement always gets our sender key,
+    // since the member may have rejoined without it; the first time a member shows
+    // up we also announce ourselves again, they may have joined after we did
+    pub fn handle_public_key(
+        &mut self,
+        announcement: KeyAnnouncement,
+    ) -> Result<Vec<ClientFrame>, GroupError> {
+        if announcement.name == self.name {
+            return Ok(vec![]);
+        }
+        if let Some(peer) = self.peers.get(&announcement.name) {
+            if peer.public_key != announcement.public_key {
+                return Err(GroupError::KeyChanged(announcement.name));
+            }
+            return Ok(vec![self.deliver_sender_key(&peer.public_key)]);
+        }
+
+        let delivery = self.deliver_sender_key(&announcement.public_key);
+        self.peers.insert(
+            announcement.name,
+            Peer {
+                public_key: announcement.public_key,
+                sender_key: None,
+            },
+        );
+        Ok(vec![self.publish(), delivery])
+    }
+
+    // a member left: their keys go, and so does our sender key, which they hold; the
+    // returned frames hand a new one to everyone still here
+    pub fn forget(&mut self, name: &str) -> Vec<ClientFrame> {
+        if self.peers.remove(name).is_none() {
+            return vec![];
+        }
+        self.previous_sender_key = Some(self.sender_key);
+        self.sender_key = security::generate_sender_key();
+        self.sender_key_deliveries()
+    }
+
+    // forgets the members missing from the room's member list, who left while we
+    // were away
+    pub fn retain(&mut self, names: &[String]) -> Vec<ClientFrame> {
+        let gone = self
+            .peers
+            .keys()
+            .filter(|name| !names.contains(name))
+            .cloned()
+            .collect::<Vec<String>>();
+        let mut frames = vec![];
+        for name in gone {
+            frames = self.forget(&name);
+        }
+        frames
+    }
+
+    pub fn handle_sender_key(&mut self, delivery: SenderKeyDelivery) -> Result<(), GroupError> {
+        // deliveries are relayed to the whole room, most of them are for someone else
+        if delivery.recipient != self.identity.public_key() {
+            return Ok(());
+        }
+        let peer = self
+            .peers
+            .get_mut(&delivery.name)
+            .ok_or_else(|| GroupError::UnknownSender(delivery.name.clone()))?;
+        let pairwise_key = self.identity.pairwise_key(&peer.public_key, &self.room_key);
+        let sender_key = security::open(&delivery.sealed_key, &pairwise_key)?
+            .try_into()
+            .map_err(|_| GroupError::Decrypt(DecryptError::Truncated))?;
+        peer.sender_key = Some(sender_key);
+        Ok(())
+    }
+
+    pub fn encrypt(&self, message: &str) -> ClientFrame {
+        ClientFrame::GroupMessage(GroupPayload {
+            name: self.name.clone(),
+            ciphertext: security::encrypt(message, &self.sender_key),
+        })
+    }
+
+    pub fn decrypt(&self, payload: &GroupPayload) -> Result<String, GroupError> {
+        // a server that relays our own messages back to us
+        if payload.name == self.name {
+            let result = security::open_text(&payload.ciphertext, &self.sender_key);
+            return match (result, &self.previous_sender_key) {
+                (Err(_), Some(previous)) => Ok(security::open_text(&payload.ciphertext, previous)?),
+                (result, _) => Ok(result?),
+            };
+        }
+        let peer = self
+            .peers
+            .get(&payload.name)
+            .ok_or_else(|| GroupError::UnknownSender(payload.name.clone()))?;
+        let sender_key = peer
+            .sender_key
+            .as_ref()
+            .ok_or_else(|| GroupError::NoSenderKey(payload.name.clone()))?;
+        Ok(security::open_text(&payload.ciphertext, sender_key)?)
+    }
+
+    // a message for one member, under the key only the two of us can derive
+    pub fn encrypt_direct(&self, to: &str, message: &str) -> Result<ClientFrame, GroupError> {
+        let peer = self
+            .peers
+            .get(to)
+            .ok_or_else(|| GroupError::UnknownSender(to.to_string()))?;
+        let pairwise_key = self.identity.pairwise_key(&peer.public_key, &self.room_key);
+        Ok(ClientFrame::DirectMessage(DirectPayload {
+            name: self.name.clone(),
+            recipient: peer.public_key,
+            ciphertext: security::encrypt(message, &pairwise_key),
+        }))
+    }
+
+    // None for direct messages between other members
+    pub fn decrypt_direct(&self, payload: &DirectPayload) -> Result<Option<String>, GroupError> {
+        if payload.recipient != self.identity.public_key() {
+            return Ok(None);
+        }
+        let peer = self
+            .peers
+            .get(&payload.name)
+            .ok_or_else(|| GroupError::UnknownSender(payload.name.clone()))?;
+        let pairwise_key = self.identity.pairwise_key(&peer.public_key, &self.room_key);
+        Ok(Some(security::open_text(
+            &payload.ciphertext,
+            &pairwise_key,
+        )?))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::VecDeque, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{tcp::OwnedWriteHalf, TcpListener},
+        sync::Mutex,
+        time::{timeout, Duration},
+    };
+
+    use aes::{
+        cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+        Aes128,
+    };
+
+    use super::*;
+    use crate::app::codec::{self, FrameDecoder};
+    use crate::app::config::ServerAddress;
+    use crate::app::connection::{self, Connection};
+    use crate::app::protocol::ServerFrame;
+
+    // stand-in for the real server: greets, accepts any JOIN_ROOM and relays every
+    // later frame untouched to all other clients
+    pub(crate) async fn start_relay() -> ServerAddress {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let clients: Arc<Mutex<Vec<Arc<Mutex<OwnedWriteHalf>>>>> = Arc::default();
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let (mut reader, writer) = tcp_stream.into_split();
+                let writer = Arc::new(Mutex::new(writer));
+                let clients = clients.clone();
+                tokio::spawn(async move {
+                    let send = |writer: Arc<Mutex<OwnedWriteHalf>>, frame: Vec<u8>| async move {
+                        let _ = writer.lock().await.write_all(&codec::encode(&frame)).await;
+                    };
+                    send(writer.clone(), b"WELCOME_MESSAGE hi".to_vec()).await;
+                    let mut decoder = FrameDecoder::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        let Ok(bytes_read) = reader.read(&mut buf).await else {
+                            return;
+                        };
+                        if bytes_read == 0 {
+                            return;
+                        }
+                        decoder.push(&buf[..bytes_read]);
+                        while let Some(frame) = decoder.next_frame().unwrap() {
+                            if frame.starts_with(b"JOIN_ROOM") {
+                                clients.lock().await.push(writer.clone());
+                                send(
+                                    writer.clone(),
+                                    b"ROOM_JOIN_SUCCESS_MESSAGE Room ID 1".to_vec(),
+                                )
+                                .await;
+                                continue;
+                            }
+                            for client in clients.lock().await.iter() {
+                                if !Arc::ptr_eq(client, &writer) {
+                                    send(client.clone(), frame.clone()).await;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        ServerAddress {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    fn session(name: &str, room_key: RoomKey) -> GroupSession {
+        GroupSession::new(name.to_string(), room_key, IdentityKeyPair::generate())
+    }
+
+    pub(crate) async fn join(server_address: &ServerAddress, name: &str) -> Connection {
+        let (connection, _) = connection::connect(server_address.clone()).await.unwrap();
+        let join_frame = ClientFrame::join_room("1", name).unwrap();
+        let reply = connection::join_room(connection.clone(), join_frame)
+            .await
+            .unwrap();
+        assert!(matches!(reply, ServerFrame::RoomJoinSuccess(_)));
+        connection
+    }
+
+    async fn recv(connection: &Connection) -> ServerFrame {
+        timeout(Duration::from_secs(5), connection.recv())
+            .await
+            .expect("relay went quiet")
+            .unwrap()
+    }
+
+    // feeds incoming frames into the session, answering key exchange frames, until
+    // a group message arrives
+    async fn pump(session: &mut GroupSession, connection: &Connection) -> GroupPayload {
+        loop {
+            match recv(connection).await {
+                ServerFrame::PublicKey(announcement) => {
+                    for frame in session.handle_public_key(announcement).unwrap() {
+                        connection.send(&frame).await.unwrap();
+                    }
+                }
+                ServerFrame::SenderKey(delivery) => session.handle_sender_key(delivery).unwrap(),
+                ServerFrame::GroupMessage(payload) => return payload,
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_members_holding_the_sender_key_can_read() {
+        let server_address = start_relay().await;
+        let room_key = security::derive_room_key("passphrase", "1");
+
+        let alice = join(&server_address, "alice").await;
+        let bob = join(&server_address, "bob").await;
+        // eve knows the room passphrase but never takes part in the key exchange
+        let eve = join(&server_address, "eve").await;
+
+        let mut alice_session = session("alice", room_key.clone());
+        let mut bob_session = session("bob", room_key.clone());
+        let eve_session = session("eve", room_key);
+
+        alice.send(&alice_session.publish()).await.unwrap();
+
+        // bob learns alice's key, answers with his own key and his sender key
+        let ServerFrame::PublicKey(announcement) = recv(&bob).await else {
+            panic!("expected alice's public key");
+        };
+        for frame in bob_session.handle_public_key(announcement).unwrap() {
+            bob.send(&frame).await.unwrap();
+        }
+
+        // alice answers in turn and then receives bob's sender key
+        let ServerFrame::PublicKey(announcement) = recv(&alice).await else {
+            panic!("expected bob's public key");
+        };
+        for frame in alice_session.handle_public_key(announcement).unwrap() {
+            alice.send(&frame).await.unwrap();
+        }
+        let ServerFrame::SenderKey(delivery) = recv(&alice).await else {
+            panic!("expected bob's sender key");
+        };
+        alice_session.handle_sender_key(delivery).unwrap();
+
+        alice
+            .send(&alice_session.encrypt("alice > hello bob"))
+            .await
+            .unwrap();
+        let payload = pump(&mut bob_session, &bob).await;
+        assert_eq!(bob_session.decrypt(&payload).unwrap(), "alice > hello bob");
+
+        bob.send(&bob_session.encrypt("bob > hi alice"))
+            .await
+            .unwrap();
+        let payload = pump(&mut alice_session, &alice).await;
+        assert_eq!(alice_session.decrypt(&payload).unwrap(), "bob > hi alice");
+
+        // eve saw every frame go past but holds no sender key
+        let mut eve_messages = vec![];
+        while eve_messages.len() < 2 {
+            if let ServerFrame::GroupMessage(payload) = recv(&eve).await {
+                eve_messages.push(payload);
+            }
+        }
+        for payload in &eve_messages {
+            assert!(eve_session.decrypt(payload).is_err());
+            assert!(security::decrypt(
+                &payload.ciphertext,
+                security::derive_room_key("passphrase", "1").bytes()
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn sender_key_is_useless_without_the_passphrase() {
+        let mut alice = session("alice", security::derive_room_key("right", "1"));
+        let mut mallory = session("mallory", security::derive_room_key("wrong", "1"));
+
+        let ClientFrame::PublicKey(mallory_key) = mallory.publish() else {
+            unreachable!()
+        };
+        let frames = alice.handle_public_key(mallory_key).unwrap();
+        let ClientFrame::PublicKey(alice_key) = alice.publish() else {
+            unreachable!()
+        };
+        mallory.handle_public_key(alice_key).unwrap();
+
+        let ClientFrame::SenderKey(delivery) = frames[1].clone() else {
+            panic!("expected a sender key delivery");
+        };
+        assert!(matches!(
+            mallory.handle_sender_key(delivery),
+            Err(GroupError::Decrypt(DecryptError::Authentication))
+        ));
+    }
+
+    #[test]
+    fn direct_messages_reach_only_their_recipient() {
+        let room_key = security::derive_room_key("passphrase", "1");
+        let mut alice = session("alice", room_key.clone());
+        let mut bob = session("bob", room_key.clone());
+        let mut carol = session("carol", room_key);
+        let introduce = |one: &mut GroupSession, other: &mut GroupSession| {
+            let ClientFrame::PublicKey(announcement) = other.publish() else {
+                unreachable!()
+            };
+            one.handle_public_key(announcement).unwrap();
+            let ClientFrame::PublicKey(announcement) = one.publish() else {
+                unreachable!()
+            };
+            other.handle_public_key(announcement).unwrap();
+        };
+        introduce(&mut alice, &mut bob);
+        introduce(&mut carol, &mut alice);
+
+        let ClientFrame::DirectMessage(payload) = alice.encrypt_direct("bob", "psst").unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            bob.decrypt_direct(&payload).unwrap().as_deref(),
+            Some("psst")
+        );
+        // carol knows alice but the message is not for her
+        assert_eq!(carol.decrypt_direct(&payload).unwrap(), None);
+        assert!(matches!(
+            alice.encrypt_direct("dave", "hello"),
+            Err(GroupError::UnknownSender(_))
+        ));
+    }
+
+    // what the relay would do: frames go round in order to every session, which
+    // ignores its own, and whatever they answer goes round too
+    fn exchange(sessions: &mut [&mut GroupSession], first: ClientFrame) {
+        let mut frames = VecDeque::from([first]);
+        while let Some(frame) = frames.pop_front() {
+            for session in sessions.iter_mut() {
+                match frame.clone() {
+                    ClientFrame::PublicKey(announcement) => {
+                        frames.extend(session.handle_public_key(announcement).unwrap());
+                    }
+                    ClientFrame::SenderKey(delivery) => {
+                        session.handle_sender_key(delivery).unwrap()
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    fn group_payload(frame: ClientFrame) -> GroupPayload {
+        let ClientFrame::GroupMessage(payload) = frame else {
+            unreachable!()
+        };
+        payload
+    }
+
+    #[test]
+    fn members_who_leave_and_rejoin_get_keys_again() {
+        let room_key = security::derive_room_key("passphrase", "1");
+        let mut alice = session("alice", room_key.clone());
+        let mut carol = session("carol", room_key.clone());
+        let bob_identity = IdentityKeyPair::generate();
+        let mut bob = GroupSession::new("bob".to_string(), room_key.clone(), bob_identity.clone());
+        let first = alice.publish();
+        exchange(&mut [&mut alice, &mut bob, &mut carol], first);
+
+        let before = group_payload(alice.encrypt("before bob left"));
+        // bob leaves: alice and carol drop him and alice's key moves on without him
+        let mut frames = alice.forget("bob");
+        frames.extend(carol.forget("bob"));
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            let ClientFrame::SenderKey(delivery) = frame else {
+                unreachable!()
+            };
+            alice.handle_sender_key(delivery.clone()).unwrap();
+            carol.handle_sender_key(delivery).unwrap();
+        }
+        let payload = group_payload(alice.encrypt("after bob left"));
+        assert_eq!(carol.decrypt(&payload).unwrap(), "after bob left");
+        assert!(bob.decrypt(&payload).is_err());
+        // what alice sent before the change still reads as hers when relayed back
+        assert_eq!(alice.decrypt(&before).unwrap(), "before bob left");
+
+        // he comes back with the same identity, and then once more with a new one
+        for identity in [bob_identity, IdentityKeyPair::generate()] {
+            let mut bob = GroupSession::new("bob".to_string(), room_key.clone(), identity);
+            let first = bob.publish();
+            exchange(&mut [&mut alice, &mut bob, &mut carol], first);
+            let payload = group_payload(alice.encrypt("welcome back"));
+            assert_eq!(bob.decrypt(&payload).unwrap(), "welcome back");
+            let payload = group_payload(bob.encrypt("thanks"));
+            assert_eq!(alice.decrypt(&payload).unwrap(), "thanks");
+            assert_eq!(carol.decrypt(&payload).unwrap(), "thanks");
+            alice.forget("bob");
+            carol.forget("bob");
+        }
+    }
+
+    #[test]
+    fn rejects_ciphertext_that_is_not_sealed() {
+        let room_key = security::derive_room_key("passphrase", "1");
+        let mut alice = session("alice", room_key.clone());
+        let mut bob = session("bob", room_key);
+        let first = alice.publish();
+        exchange(&mut [&mut alice, &mut bob], first);
+
+        // what a client that predates the envelope would send, 16 bytes of aes-ecb
+        let ecb = |key: &[u8; 16]| {
+            let mut block = *GenericArray::from_slice(
+                b"hi\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e\x0e",
+            );
+            Aes128::new(GenericArray::from_slice(key)).encrypt_block(&mut block);
+            block.to_vec()
+        };
+        let payload = GroupPayload {
+            name: "alice".to_string(),
+            ciphertext: ecb(&alice.sender_key),
+        };
+        assert_eq!(
+            security::decrypt(&payload.ciphertext, &alice.sender_key).as_deref(),
+            Ok("hi")
+        );
+        assert!(bob.decrypt(&payload).is_err());
+        assert!(alice.decrypt(&payload).is_err());
+
+        let pairwise_key = alice
+            .identity
+            .pairwise_key(&bob.identity.public_key(), &alice.room_key);
+        let payload = DirectPayload {
+            name: "alice".to_string(),
+            recipient: bob.identity.public_key(),
+            ciphertext: ecb(&pairwise_key),
+        };
+        assert!(bob.decrypt_direct(&payload).is_err());
+    }
+
+    #[test]
+    fn reads_its_own_messages_relayed_back() {
+        let alice = session("alice", security::derive_room_key("passphrase", "1"));
+        let ClientFrame::GroupMessage(payload) = alice.encrypt("hello") else {
+            unreachable!()
+        };
+        assert_eq!(alice.decrypt(&payload).unwrap(), "hello");
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::codec::{self, FrameDecoder};
+use super::message::ConversationMessage;
+use super::security;
+use super::timestamp;
+
+const SALT_FILE: &str = "salt";
+const CHECK_FILE: &str = "check";
+const CHECK_PLAINTEXT: &[u8] = b"letschat-history";
+const SALT_LEN: usize = 16;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryError {
+    WrongPassphrase,
+    Io(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::WrongPassphrase => {
+                write!(f, "the passphrase does not match the stored history")
+            }
+            HistoryError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for HistoryError {
+    fn from(e: io::Error) -> Self {
+        HistoryError::Io(e.to_string())
+    }
+}
+
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join("letschat").join("history"))
+}
+
+// unlocked history for every room: one append-only log per server, room and name, each
+// record sealed with a key derived from the local passphrase
+#[derive(Clone)]
+pub struct HistoryStore {
+    dir: PathBuf,
+    key: [u8; 16],
+    retention: Duration,
+}
+
+impl fmt::Debug for HistoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HistoryStore({})", self.dir.display())
+    }
+}
+
+impl HistoryStore {
+    // derives the key from the passphrase and the salt kept next to the logs; slow on
+    // purpose, keep it off the ui thread. the first unlock of a directory sets the
+    // passphrase, later ones have to match it
+    pub fn unlock(dir: &Path, passphrase: &str, retention_days: u32) -> Result<Self, HistoryError> {
+        fs::create_dir_all(dir)?;
+        let salt_path = dir.join(SALT_FILE);
+        let salt = match fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut salt = vec![0u8; SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+                fs::write(&salt_path, &salt)?;
+                salt
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = security::derive_key(passphrase, &salt);
+
+        let check_path = dir.join(CHECK_FILE);
+        match fs::read(&check_path) {
+            Ok(check) => {
+                if security::open(&check, &key).ok().as_deref() != Some(CHECK_PLAINTEXT) {
+                    return Err(HistoryError::WrongPassphrase);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::write(&check_path, security::seal(CHECK_PLAINTEXT, &key))?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(HistoryStore {
+            dir: dir.to_path_buf(),
+            key,
+            retention: Duration::from_secs(u64::from(retention_days) * SECONDS_PER_DAY),
+        })
+    }
+
+    // loads what is kept for the room, dropping messages past the retention period;
+    // a later record of a message, written when it was edited or deleted, takes the
+    // place of the earlier one
+    pub fn open_room(
+        &self,
+        server: &str,
+        room_id: &str,
+        name: &str,
+    ) -> Result<(RoomHistory, Vec<ConversationMessage>), HistoryError> {
+        let room_history = RoomHistory {
+            path: self.dir.join(log_file_name(server, room_id, name)),
+            key: self.key,
+        };
+        let bytes = match fs::read(&room_history.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((room_history, vec![])),
+            Err(e) => return Err(e.into()),
+        };
+
+        let oldest = timestamp::unix_now().saturating_sub(self.retention.as_secs());
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let mut messages: Vec<ConversationMessage> = vec![];
+        // position of each message id in `messages`
+        let mut positions = HashMap::new();
+        let mut needs_rewrite = false;
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(record)) => {
+                    let message = security::open(&record, &self.key)
+                        .ok()
+                        .and_then(|plaintext| ConversationMessage::from_record(&plaintext));
+                    match message {
+                        Some(message) if message.received_at >= oldest => {
+                            match positions.get(&message.id) {
+                                // compacted so the old text does not linger on disk
+                                Some(&position) => {
+                                    messages[position] = message;
+                                    needs_rewrite = true;
+                                }
+                                None => {
+                                    positions.insert(message.id.clone(), messages.len());
+                                    messages.push(message);
+                                }
+                            }
+                        }
+                        // expired or unreadable
+                        _ => needs_rewrite = true,
+                    }
+                }
+                Ok(None) => {
+                    // a write cut short by a crash leaves a partial record at the end
+                    needs_rewrite |= decoder.has_pending_bytes();
+                    break;
+                }
+                Err(_) => {
+                    needs_rewrite = true;
+                    break;
+                }
+            }
+        }
+
+        if needs_rewrite {
+            room_history.rewrite(&messages)?;
+        }
+        Ok((room_history, messages))
+    }
+}
+
+pub struct RoomHistory {
+    path: PathBuf,
+    key: [u8; 16],
+}
+
+impl RoomHistory {
+    pub fn append(&self, message: &ConversationMessage) -> Result<(), HistoryError> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&self.record(message))?;
+        Ok(())
+    }
+
+    fn record(&self, message: &ConversationMessage) -> Vec<u8> {
+        codec::encode(&security::seal(&message.to_record(), &self.key))
+    }
+
+    // writes to a temporary file first so a crash never leaves a half-written log
+    fn rewrite(&self, messages: &[ConversationMessage]) -> Result<(), HistoryError> {
+        let tmp_path = self.path.with_extension("tmp");
+        let bytes: Vec<u8> = messages.iter().flat_map(|m| self.record(m)).collect();
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+// hashed so room ids and host names never have to be valid file names
+fn log_file_name(server: &str, room_id: &str, name: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(server.as_bytes())
+        .chain_update(b"\n")
+        .chain_update(room_id.as_bytes())
+        .chain_update(b"\n")
+        .chain_update(name.as_bytes())
+        .finalize();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.log", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::message::{MessageKind, Revision};
+
+    fn chat(name: &str, content: &str) -> ConversationMessage {
+        ConversationMessage {
+            sender_name: name.to_string(),
+            ..ConversationMessage::notice(MessageKind::Chat, content.to_string())
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("letschat-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn keeps_messages_encrypted_across_unlocks() {
+        let dir = temp_dir("roundtrip");
+        let store = HistoryStore::unlock(&dir, "local secret", 30).unwrap();
+        let (room_history, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert!(messages.is_empty());
+
+        let hello = chat("alice", "hello\nworld");
+        let reply = chat("You", "hi alice");
+        room_history.append(&hello).unwrap();
+        room_history.append(&reply).unwrap();
+
+        let on_disk = fs::read(dir.join(log_file_name("localhost:8000", "1", "bob"))).unwrap();
+        assert!(!on_disk.windows(5).any(|w| w == b"hello"));
+
+        let store = HistoryStore::unlock(&dir, "local secret", 30).unwrap();
+        let (_, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert_eq!(messages, vec![hello, reply]);
+        // other rooms, servers and names have their own log
+        let (_, other_server) = store.open_room("localhost:8001", "1", "bob").unwrap();
+        let (_, other_name) = store.open_room("localhost:8000", "1", "carol").unwrap();
+        assert!(other_server.is_empty() && other_name.is_empty());
+
+        assert_eq!(
+            HistoryStore::unlock(&dir, "guess", 30).err(),
+            Some(HistoryError::WrongPassphrase)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn later_records_replace_earlier_ones() {
+        let dir = temp_dir("revisions");
+        let store = HistoryStore::unlock(&dir, "local secret", 30).unwrap();
+        let (room_history, _) = store.open_room("localhost:8000", "1", "bob").unwrap();
+
+        let mut typo = chat("alice", "helo");
+        let secret = chat("alice", "my password is hunter2");
+        room_history.append(&typo).unwrap();
+        room_history.append(&secret).unwrap();
+        typo.revise(Revision::Edited, "hello".to_string());
+        let mut deleted = secret.clone();
+        deleted.revise(Revision::Deleted, String::new());
+        room_history.append(&typo).unwrap();
+        room_history.append(&deleted).unwrap();
+
+        let (_, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert_eq!(messages, vec![typo.clone(), deleted.clone()]);
+        // reading it again finds the compacted log
+        let (_, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert_eq!(messages, vec![typo, deleted]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_expired_and_torn_records() {
+        let dir = temp_dir("retention");
+        let store = HistoryStore::unlock(&dir, "local secret", 7).unwrap();
+        let (room_history, _) = store.open_room("localhost:8000", "1", "bob").unwrap();
+
+        let mut old = chat("alice", "last month");
+        old.received_at -= 30 * SECONDS_PER_DAY;
+        let recent = chat("alice", "today");
+        room_history.append(&old).unwrap();
+        room_history.append(&recent).unwrap();
+        // a record cut short by a crash
+        let torn = room_history.record(&recent);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&room_history.path)
+            .unwrap()
+            .write_all(&torn[..torn.len() / 2])
+            .unwrap();
+
+        let (room_history, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert_eq!(messages, vec![recent.clone()]);
+
+        // the log was compacted, so appending after it still reads back cleanly
+        let later = chat("You", "later");
+        room_history.append(&later).unwrap();
+        let (_, messages) = store.open_room("localhost:8000", "1", "bob").unwrap();
+        assert_eq!(messages, vec![recent, later]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
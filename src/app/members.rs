@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use super::protocol::Presence;
+
+// who is in a room right now, as far as the server and the members have told us
+#[derive(Debug, Default)]
+pub struct Roster {
+    members: BTreeMap<String, Presence>,
+}
+
+impl Roster {
+    pub fn new() -> Self {
+        Roster::default()
+    }
+
+    // the server's answer to a member list request replaces what we had, keeping the
+    // presence of members we already knew
+    pub fn replace(&mut self, names: Vec<String>) {
+        let mut members = BTreeMap::new();
+        for name in names {
+            let presence = self.members.get(&name).copied().unwrap_or(Presence::Online);
+            members.insert(name, presence);
+        }
+        self.members = members;
+    }
+
+    // true if the member was not in the room yet
+    pub fn joined(&mut self, name: &str) -> bool {
+        if self.members.contains_key(name) {
+            return false;
+        }
+        self.members.insert(name.to_string(), Presence::Online);
+        true
+    }
+
+    // true if the member was in the room
+    pub fn left(&mut self, name: &str) -> bool {
+        self.members.remove(name).is_some()
+    }
+
+    // a presence update also tells us the member is here
+    pub fn set_presence(&mut self, name: &str, presence: Presence) {
+        self.members.insert(name.to_string(), presence);
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Presence)> {
+        self.members
+            .iter()
+            .map(|(name, presence)| (name.as_str(), *presence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_joins_leaves_and_presence() {
+        let mut roster = Roster::new();
+        assert!(roster.joined("alice"));
+        assert!(!roster.joined("alice"));
+        roster.set_presence("alice", Presence::InVoice);
+        roster.set_presence("bob", Presence::Idle);
+
+        // a fresh list drops bob, who left unnoticed, and keeps what we knew about alice
+        roster.replace(vec!["alice".to_string(), "carol".to_string()]);
+        assert_eq!(
+            roster.iter().collect::<Vec<_>>(),
+            vec![("alice", Presence::InVoice), ("carol", Presence::Online)]
+        );
+
+        assert!(roster.left("carol"));
+        assert!(!roster.left("carol"));
+        assert_eq!(roster.len(), 1);
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rand::Rng;
+
+use super::timestamp;
+
+// first line of every history record, so a later layout can be told apart
+const RECORD_VERSION: &str = "v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Chat,
+    System,
+    Join,
+    Leave,
+    Voice,
+    Error,
+}
+
+impl MessageKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Chat => "chat",
+            MessageKind::System => "system",
+            MessageKind::Join => "join",
+            MessageKind::Leave => "leave",
+            MessageKind::Voice => "voice",
+            MessageKind::Error => "error",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "chat" => MessageKind::Chat,
+            "system" => MessageKind::System,
+            "join" => MessageKind::Join,
+            "leave" => MessageKind::Leave,
+            "voice" => MessageKind::Voice,
+            "error" => MessageKind::Error,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryState {
+    // ours, waiting for the server to take it, or queued until we are back online
+    Pending,
+    // ours, taken by the server
+    Sent,
+    // ours, never left this machine
+    Failed,
+    // someone else's, or a local notice
+    Received,
+}
+
+impl DeliveryState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Pending => "pending",
+            DeliveryState::Sent => "sent",
+            DeliveryState::Failed => "failed",
+            DeliveryState::Received => "received",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "pending" => DeliveryState::Pending,
+            "sent" => DeliveryState::Sent,
+            "failed" => DeliveryState::Failed,
+            "received" => DeliveryState::Received,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revision {
+    Original,
+    Edited,
+    // a tombstone, the content is gone
+    Deleted,
+}
+
+impl Revision {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Revision::Original => "original",
+            Revision::Edited => "edited",
+            Revision::Deleted => "deleted",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "original" => Revision::Original,
+            "edited" => Revision::Edited,
+            "deleted" => Revision::Deleted,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversationMessage {
+    // shared by every member for chat messages, local for notices
+    pub id: String,
+    pub kind: MessageKind,
+    // fingerprint of the author's identity key, None when it could not be verified
+    pub sender_id: Option<String>,
+    pub sender_name: String,
+    pub content: String,
+    // the author's clock, unix seconds
+    pub sent_at: Option<u64>,
+    // our clock, unix seconds
+    pub received_at: u64,
+    pub delivery: DeliveryState,
+    // id of the message this one answers
+    pub reply_to: Option<String>,
+    pub revision: Revision,
+}
+
+impl ConversationMessage {
+    // a notice shown in the conversation, not written by any member
+    pub fn notice(kind: MessageKind, content: String) -> Self {
+        ConversationMessage {
+            id: new_message_id(),
+            kind,
+            sender_id: None,
+            sender_name: String::new(),
+            content,
+            sent_at: None,
+            received_at: timestamp::unix_now(),
+            delivery: DeliveryState::Received,
+            reply_to: None,
+            revision: Revision::Original,
+        }
+    }
+
+    pub fn is_own(&self) -> bool {
+        self.delivery != DeliveryState::Received
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.revision == Revision::Deleted
+    }
+
+    // a change the author sent later; deleting drops the content for good
+    pub fn revise(&mut self, revision: Revision, content: String) {
+        self.revision = revision;
+        self.content = match revision {
+            Revision::Deleted => String::new(),
+            Revision::Original | Revision::Edited => content,
+        };
+    }
+
+    // the time to show: when it was written if known, otherwise when it got here
+    pub fn time(&self) -> u64 {
+        self.sent_at.unwrap_or(self.received_at)
+    }
+
+    // one line per header field, then the content, which may hold newlines
+    pub fn to_record(&self) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            RECORD_VERSION,
+            self.id,
+            self.kind.as_str(),
+            self.sender_id.as_deref().unwrap_or(""),
+            self.sender_name,
+            self.sent_at.map(|t| t.to_string()).unwrap_or_default(),
+            self.received_at,
+            self.delivery.as_str(),
+            self.reply_to.as_deref().unwrap_or(""),
+            self.revision.as_str(),
+            self.content
+        )
+        .into_bytes()
+    }
+
+    pub fn from_record(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut fields = text.splitn(11, '\n');
+        if fields.next()? != RECORD_VERSION {
+            return None;
+        }
+        let id = fields.next()?.to_string();
+        let kind = MessageKind::parse(fields.next()?)?;
+        let sender_id = Some(fields.next()?.to_string()).filter(|s| !s.is_empty());
+        let sender_name = fields.next()?.to_string();
+        let sent_at = match fields.next()? {
+            "" => None,
+            t => Some(t.parse().ok()?),
+        };
+        Some(ConversationMessage {
+            id,
+            kind,
+            sender_id,
+            sender_name,
+            sent_at,
+            received_at: fields.next()?.parse().ok()?,
+            delivery: DeliveryState::parse(fields.next()?)?,
+            reply_to: Some(fields.next()?.to_string()).filter(|s| !s.is_empty()),
+            revision: Revision::parse(fields.next()?)?,
+            content: fields.next()?.to_string(),
+        })
+    }
+}
+
+// the message a reply chain starts from; a parent we do not have ends the chain
+pub fn thread_root<'a>(messages: &'a [ConversationMessage], id: &'a str) -> &'a str {
+    let mut root = id;
+    // bounded, in case someone sends replies that go round in a circle
+    for _ in 0..messages.len() {
+        let parent = messages
+            .iter()
+            .find(|cm| cm.id == root)
+            .and_then(|cm| cm.reply_to.as_deref())
+            .filter(|parent| messages.iter().any(|cm| cm.id == *parent));
+        match parent {
+            Some(parent) => root = parent,
+            None => break,
+        }
+    }
+    root
+}
+
+// the root and every message replying to it, directly or further down, in the order
+// they are in the conversation
+pub fn thread(messages: &[ConversationMessage], root: &str) -> Vec<ConversationMessage> {
+    messages
+        .iter()
+        .filter(|cm| {
+            cm.id == root || (cm.reply_to.is_some() && thread_root(messages, &cm.id) == root)
+        })
+        .cloned()
+        .collect()
+}
+
+// who reacted to one message with what, by member name
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Reactions(BTreeMap<String, BTreeSet<String>>);
+
+impl Reactions {
+    pub fn has(&self, emoji: &str, name: &str) -> bool {
+        self.0.get(emoji).is_some_and(|names| names.contains(name))
+    }
+
+    pub fn set(&mut self, emoji: &str, name: &str, added: bool) {
+        if added {
+            self.0
+                .entry(emoji.to_string())
+                .or_default()
+                .insert(name.to_string());
+        } else if let Some(names) = self.0.get_mut(emoji) {
+            names.remove(name);
+            if names.is_empty() {
+                self.0.remove(emoji);
+            }
+        }
+    }
+
+    // every emoji someone reacted with, and who did
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &BTreeSet<String>)> {
+        self.0.iter().map(|(emoji, names)| (emoji.as_str(), names))
+    }
+}
+
+pub fn new_message_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let message = ConversationMessage {
+            id: new_message_id(),
+            kind: MessageKind::Chat,
+            sender_id: Some("00ff00ff00ff00ff".to_string()),
+            sender_name: "alice".to_string(),
+            content: "a > b\nsecond line".to_string(),
+            sent_at: Some(1_700_000_000),
+            received_at: 1_700_000_002,
+            delivery: DeliveryState::Received,
+            reply_to: Some(new_message_id()),
+            revision: Revision::Edited,
+        };
+        assert_eq!(
+            ConversationMessage::from_record(&message.to_record()),
+            Some(message)
+        );
+
+        let notice = ConversationMessage::notice(MessageKind::Leave, "You left".to_string());
+        assert_eq!(
+            ConversationMessage::from_record(&notice.to_record()),
+            Some(notice)
+        );
+    }
+
+    #[test]
+    fn gathers_threads_from_replies() {
+        let chat = |id: &str, reply_to: Option<&str>| ConversationMessage {
+            id: id.to_string(),
+            reply_to: reply_to.map(str::to_string),
+            ..ConversationMessage::notice(MessageKind::Chat, id.to_string())
+        };
+        let messages = vec![
+            chat("a", None),
+            chat("b", Some("a")),
+            chat("c", None),
+            chat("d", Some("b")),
+            // its parent was never seen here
+            chat("e", Some("gone")),
+            // a loop, which only a misbehaving client sends
+            chat("x", Some("y")),
+            chat("y", Some("x")),
+        ];
+        assert_eq!(thread_root(&messages, "d"), "a");
+        assert_eq!(thread_root(&messages, "e"), "e");
+        let ids = |thread: Vec<ConversationMessage>| {
+            thread.into_iter().map(|cm| cm.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(thread(&messages, "a")), vec!["a", "b", "d"]);
+        assert_eq!(ids(thread(&messages, "c")), vec!["c"]);
+        let root = thread_root(&messages, "x");
+        assert!(root == "x" || root == "y");
+    }
+
+    #[test]
+    fn reactions_toggle_per_member() {
+        let mut reactions = Reactions::default();
+        reactions.set("👍", "alice", true);
+        reactions.set("👍", "bob", true);
+        reactions.set("🎉", "bob", true);
+        // the same reaction twice still counts once
+        reactions.set("👍", "bob", true);
+        assert!(reactions.has("👍", "bob"));
+        let counts = |reactions: &Reactions| {
+            reactions
+                .iter()
+                .map(|(emoji, names)| (emoji.to_string(), names.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            counts(&reactions),
+            vec![("🎉".to_string(), 1), ("👍".to_string(), 2)]
+        );
+
+        reactions.set("🎉", "bob", false);
+        reactions.set("👍", "carol", false);
+        assert!(!reactions.has("🎉", "bob"));
+        assert_eq!(counts(&reactions), vec![("👍".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,356 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use iced::advanced::graphics::core::font;
+use iced::widget::{button, column, container, row, scrollable, text, text_input, Column};
+use iced::{alignment, Alignment, Element, Font, Length, Task};
+
+use super::config::{Config, Profile, ServerAddress};
+use super::history::{self, HistoryError, HistoryStore};
+use super::security::IdentityKeyPair;
+
+// where to connect and who to be there, handed from the picker to the welcome form
+#[derive(Clone)]
+pub struct Account {
+    pub server_address: ServerAddress,
+    pub name: String,
+    pub rooms: Vec<String>,
+    pub identity: IdentityKeyPair,
+    // None keeps no history on disk
+    pub history: Option<HistoryStore>,
+}
+
+#[derive(Default)]
+pub struct ProfilesViewState {
+    status_message: String,
+    history_passphrase_text: String,
+    // waiting for the history passphrase to be checked
+    pending_account: Option<Account>,
+    profile_name_text: String,
+    server_text: String,
+    username_text: String,
+    rooms_text: String,
+}
+
+impl ProfilesViewState {
+    pub fn new(config: &Config) -> Self {
+        ProfilesViewState {
+            server_text: config.server_address.to_string(),
+            ..ProfilesViewState::default()
+        }
+    }
+
+    // unlocks local history first when a passphrase was given
+    fn open(&mut self, account: Account, history_days: u32) -> ProfilesViewAction {
+        let passphrase = self.history_passphrase_text.clone();
+        if passphrase.is_empty() || history_days == 0 {
+            return ProfilesViewAction::Open(account);
+        }
+        let Some(dir) = history::default_dir() else {
+            return ProfilesViewAction::Open(account);
+        };
+        self.status_message = String::from("Unlocking history…");
+        self.pending_account = Some(account);
+        ProfilesViewAction::Run(Task::perform(
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    HistoryStore::unlock(&dir, &passphrase, history_days)
+                })
+                .await
+                .map_err(|e| HistoryError::Io(e.to_string()))?
+            },
+            ProfilesViewMessage::HistoryUnlocked,
+        ))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ProfilesViewMessage {
+    UseProfile(usize),
+    DeleteProfile(usize),
+    ProfileNameChanged(String),
+    ServerChanged(String),
+    UsernameChanged(String),
+    RoomsChanged(String),
+    HistoryPassphraseChanged(String),
+    SaveProfile,
+    ContinueWithoutProfile,
+    HistoryUnlocked(Result<HistoryStore, HistoryError>),
+}
+
+pub enum ProfilesViewAction {
+    Open(Account),
+    Run(Task<ProfilesViewMessage>),
+    None,
+}
+
+pub fn update(
+    profiles_view_state: &mut ProfilesViewState,
+    config: &mut Config,
+    message: ProfilesViewMessage,
+) -> ProfilesViewAction {
+    match message {
+        ProfilesViewMessage::UseProfile(index) => {
+            if profiles_view_state.pending_account.is_some() {
+                return ProfilesViewAction::None;
+            }
+            let Some(profile) = config.profiles.get(index).cloned() else {
+                return ProfilesViewAction::None;
+            };
+            let identity = match config.identity_key(&profile.name).and_then(decode_identity) {
+                Some(identity) => identity,
+                None => {
+                    // first use, or the saved key is unreadable: make one and keep it
+                    let identity = IdentityKeyPair::generate();
+                    let key = STANDARD.encode(identity.secret_bytes());
+                    config.set_identity_key(&profile.name, Some(key));
+                    if let Err(e) = config.save_profiles() {
+                        println!("Could not save profiles: {}", e);
+                    }
+                    identity
+                }
+            };
+            let account = Account {
+                server_address: profile.server_address(),
+                name: profile.username,
+                rooms: profile.rooms,
+                identity,
+                history: None,
+            };
+            profiles_view_state.open(account, config.history_days)
+        }
+        ProfilesViewMessage::DeleteProfile(index) => {
+            if index < config.profiles.len() {
+                let profile = config.profiles.remove(index);
+                config.set_identity_key(&profile.name, None);
+                profiles_view_state.status_message = match config.save_profiles() {
+                    Ok(()) => format!("Deleted {}", profile.name),
+                    Err(e) => format!("Could not save profiles: {}", e),
+                };
+            }
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::ProfileNameChanged(s) => {
+            profiles_view_state.profile_name_text = s;
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::ServerChanged(s) => {
+            profiles_view_state.server_text = s;
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::UsernameChanged(s) => {
+            profiles_view_state.username_text = s;
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::RoomsChanged(s) => {
+            profiles_view_state.rooms_text = s;
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::HistoryPassphraseChanged(s) => {
+            profiles_view_state.history_passphrase_text = s;
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::SaveProfile => {
+            let name = profiles_view_state.profile_name_text.trim().to_string();
+            if name.is_empty() {
+                profiles_view_state.status_message = String::from("Give the profile a name");
+                return ProfilesViewAction::None;
+            }
+            let Some(server_address) = ServerAddress::parse(&profiles_view_state.server_text)
+            else {
+                profiles_view_state.status_message = format!(
+                    "\"{}\" is not a valid server address, expected host:port",
+                    profiles_view_state.server_text
+                );
+                return ProfilesViewAction::None;
+            };
+            let profile = Profile {
+                name: name.clone(),
+                host: server_address.host,
+                port: server_address.port,
+                username: profiles_view_state.username_text.trim().to_string(),
+                rooms: profiles_view_state
+                    .rooms_text
+                    .split([',', ' '])
+                    .filter(|room| !room.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                ..Profile::default()
+            };
+            // saving under an existing name updates that profile but keeps its identity,
+            // which goes by the name
+            match config.profiles.iter_mut().find(|p| p.name == name) {
+                Some(existing) => *existing = profile,
+                None => config.profiles.push(profile),
+            }
+            profiles_view_state.status_message = match config.save_profiles() {
+                Ok(()) => format!("Saved {}", name),
+                Err(e) => format!("Could not save profiles: {}", e),
+            };
+            ProfilesViewAction::None
+        }
+        ProfilesViewMessage::ContinueWithoutProfile => {
+            if profiles_view_state.pending_account.is_some() {
+                return ProfilesViewAction::None;
+            }
+            let account = Account {
+                server_address: config.server_address.clone(),
+                name: String::new(),
+                rooms: vec![],
+                identity: IdentityKeyPair::generate(),
+                history: None,
+            };
+            profiles_view_state.open(account, config.history_days)
+        }
+        ProfilesViewMessage::HistoryUnlocked(result) => {
+            let Some(mut account) = profiles_view_state.pending_account.take() else {
+                return ProfilesViewAction::None;
+            };
+            match result {
+                Ok(history) => {
+                    account.history = Some(history);
+                    ProfilesViewAction::Open(account)
+                }
+                Err(e) => {
+                    profiles_view_state.status_message = format!("Could not unlock history: {}", e);
+                    ProfilesViewAction::None
+                }
+            }
+        }
+    }
+}
+
+fn decode_identity(encoded: &str) -> Option<IdentityKeyPair> {
+    let secret: [u8; 32] = STANDARD.decode(encoded).ok()?.try_into().ok()?;
+    Some(IdentityKeyPair::from_secret_bytes(secret))
+}
+
+pub fn view<'a>(
+    profiles_view_state: &'a ProfilesViewState,
+    config: &'a Config,
+) -> Element<'a, ProfilesViewMessage> {
+    let mut title_font = Font::with_name("clash-grotesk-variable");
+    title_font.weight = font::Weight::Semibold;
+    let title: Element<ProfilesViewMessage> = text("LetsChat!")
+        .size(30)
+        .font(title_font)
+        .align_x(alignment::Horizontal::Center)
+        .into();
+
+    let profile_rows = config
+        .profiles
+        .iter()
+        .enumerate()
+        .map(|(index, profile)| {
+            let label = if profile.username.is_empty() {
+                format!("{} ({})", profile.name, profile.server_address())
+            } else {
+                format!(
+                    "{} ({} as {})",
+                    profile.name,
+                    profile.server_address(),
+                    profile.username
+                )
+            };
+            let use_btn: Element<ProfilesViewMessage> = button(text(label))
+                .on_press(ProfilesViewMessage::UseProfile(index))
+                .width(Length::Fill)
+                .padding(10)
+                .into();
+            let delete_btn: Element<ProfilesViewMessage> = button("Delete")
+                .on_press(ProfilesViewMessage::DeleteProfile(index))
+                .style(button::danger)
+                .padding(10)
+                .into();
+            row![use_btn, delete_btn]
+                .spacing(10)
+                .align_y(Alignment::Center)
+                .into()
+        })
+        .collect::<Vec<Element<ProfilesViewMessage>>>();
+
+    let profiles_list: Element<ProfilesViewMessage> = if profile_rows.is_empty() {
+        text("No saved profiles yet").size(16).into()
+    } else {
+        scrollable(Column::from_vec(profile_rows).spacing(10))
+            .height(Length::Shrink)
+            .into()
+    };
+
+    let history_passphrase_ip: Element<ProfilesViewMessage> = text_input(
+        "Local history passphrase (leave empty to keep no history)",
+        &profiles_view_state.history_passphrase_text,
+    )
+    .padding(10)
+    .size(16)
+    .secure(true)
+    .on_input(ProfilesViewMessage::HistoryPassphraseChanged)
+    .into();
+
+    let continue_btn: Element<ProfilesViewMessage> = button("Continue without a profile")
+        .on_press(ProfilesViewMessage::ContinueWithoutProfile)
+        .style(button::secondary)
+        .padding(10)
+        .into();
+
+    let form_title: Element<ProfilesViewMessage> =
+        text("New profile").size(20).font(title_font).into();
+
+    let profile_name_ip: Element<ProfilesViewMessage> =
+        text_input("Profile name", &profiles_view_state.profile_name_text)
+            .padding(10)
+            .size(16)
+            .on_input(ProfilesViewMessage::ProfileNameChanged)
+            .into();
+
+    let server_ip: Element<ProfilesViewMessage> = text_input(
+        "Server address (host:port)",
+        &profiles_view_state.server_text,
+    )
+    .padding(10)
+    .size(16)
+    .on_input(ProfilesViewMessage::ServerChanged)
+    .into();
+
+    let username_ip: Element<ProfilesViewMessage> =
+        text_input("Default name", &profiles_view_state.username_text)
+            .padding(10)
+            .size(16)
+            .on_input(ProfilesViewMessage::UsernameChanged)
+            .into();
+
+    let rooms_ip: Element<ProfilesViewMessage> = text_input(
+        "Default rooms, comma separated",
+        &profiles_view_state.rooms_text,
+    )
+    .padding(10)
+    .size(16)
+    .on_input(ProfilesViewMessage::RoomsChanged)
+    .on_submit(ProfilesViewMessage::SaveProfile)
+    .into();
+
+    let save_btn: Element<ProfilesViewMessage> = button("Save profile")
+        .on_press(ProfilesViewMessage::SaveProfile)
+        .padding(12)
+        .into();
+
+    let status_text: Element<ProfilesViewMessage> =
+        text(&profiles_view_state.status_message).size(16).into();
+
+    let content: Element<ProfilesViewMessage> = column![
+        title,
+        history_passphrase_ip,
+        profiles_list,
+        continue_btn,
+        form_title,
+        profile_name_ip,
+        server_ip,
+        username_ip,
+        rooms_ip,
+        save_btn,
+        status_text
+    ]
+    .spacing(15)
+    .align_x(Alignment::Center)
+    .into();
+
+    container(content).padding(40).into()
+}
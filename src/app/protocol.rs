@@ -0,0 +1,800 @@
+use std::fmt;
+
+use base64::Engine;
+
+const JOIN_ROOM: &str = "JOIN_ROOM";
+const LEAVE_ROOM: &str = "LEAVE_ROOM";
+const NORMAL_MESSAGE: &str = "NORMAL_MESSAGE";
+const JOIN_VOICE_CHANNEL_MESSAGE: &str = "JOIN_VOICE_CHANNEL_MESSAGE";
+const LEAVE_VOICE_CHANNEL_MESSAGE: &str = "LEAVE_VOICE_CHANNEL_MESSAGE";
+const VOICE_CHANNEL_OFFER_MESSAGE: &str = "VOICE_CHANNEL_OFFER_MESSAGE";
+const WELCOME_MESSAGE: &str = "WELCOME_MESSAGE";
+const ROOM_JOIN_SUCCESS_MESSAGE: &str = "ROOM_JOIN_SUCCESS_MESSAGE";
+const ROOM_JOIN_FAILURE_MESSAGE: &str = "ROOM_JOIN_FAILURE_MESSAGE";
+const SYSTEM_MESSAGE: &str = "SYSTEM_MESSAGE";
+const PUBLIC_KEY_MESSAGE: &str = "PUBLIC_KEY_MESSAGE";
+const SENDER_KEY_MESSAGE: &str = "SENDER_KEY_MESSAGE";
+const GROUP_MESSAGE: &str = "GROUP_MESSAGE";
+const DIRECT_MESSAGE: &str = "DIRECT_MESSAGE";
+const TYPING_MESSAGE: &str = "TYPING_MESSAGE";
+const LIST_MEMBERS: &str = "LIST_MEMBERS";
+const MEMBER_LIST_MESSAGE: &str = "MEMBER_LIST_MESSAGE";
+const MEMBER_JOINED_MESSAGE: &str = "MEMBER_JOINED_MESSAGE";
+const MEMBER_LEFT_MESSAGE: &str = "MEMBER_LEFT_MESSAGE";
+const PRESENCE_MESSAGE: &str = "PRESENCE_MESSAGE";
+const TYPING_STARTED: &str = "started";
+const TYPING_STOPPED: &str = "stopped";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Empty,
+    UnknownKind(String),
+    MissingField(&'static str, &'static str),
+    InvalidField(&'static str, String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(f, "empty frame"),
+            ProtocolError::UnknownKind(kind) => write!(f, "unknown frame kind {}", kind),
+            ProtocolError::MissingField(kind, field) => {
+                write!(f, "{} frame is missing its {}", kind, field)
+            }
+            ProtocolError::InvalidField(field, reason) => {
+                write!(f, "invalid {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+// frames the client sends to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientFrame {
+    JoinRoom { room_id: String, name: String },
+    LeaveRoom { room_id: String, name: String },
+    JoinVoiceChannel { name: String },
+    LeaveVoiceChannel { name: String },
+    ListMembers { room_id: String },
+    PublicKey(KeyAnnouncement),
+    SenderKey(SenderKeyDelivery),
+    GroupMessage(GroupPayload),
+    DirectMessage(DirectPayload),
+    Typing(TypingNotice),
+    Presence(PresenceNotice),
+}
+
+// frames the server sends to the client, NormalMessage is only sent by clients
+// that predate the group key exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerFrame {
+    Welcome(String),
+    RoomJoinSuccess(String),
+    RoomJoinFailure(String),
+    NormalMessage(Vec<u8>),
+    System(String),
+    // one name per line, since names may contain spaces
+    MemberList(Vec<String>),
+    MemberJoined(String),
+    MemberLeft(String),
+    // the answer to JoinVoiceChannel: where to send and receive voice packets
+    VoiceOffer(VoiceOffer),
+    PublicKey(KeyAnnouncement),
+    SenderKey(SenderKeyDelivery),
+    GroupMessage(GroupPayload),
+    DirectMessage(DirectPayload),
+    Typing(TypingNotice),
+    Presence(PresenceNotice),
+}
+
+// the end-to-end frames are relayed by the server as-is, so both directions share
+// their bodies; binary fields come first and the sender name, which may contain
+// spaces, always comes last
+
+// "PUBLIC_KEY_MESSAGE <public key> <name>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyAnnouncement {
+    pub name: String,
+    pub public_key: [u8; 32],
+}
+
+// "SENDER_KEY_MESSAGE <recipient public key> <sealed sender key> <name>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SenderKeyDelivery {
+    pub name: String,
+    pub recipient: [u8; 32],
+    pub sealed_key: Vec<u8>,
+}
+
+// "GROUP_MESSAGE <ciphertext> <name>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupPayload {
+    pub name: String,
+    pub ciphertext: Vec<u8>,
+}
+
+// "DIRECT_MESSAGE <recipient public key> <ciphertext> <name>", relayed like sender
+// keys; only the recipient holds the pairwise key it is encrypted with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectPayload {
+    pub name: String,
+    pub recipient: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+// "TYPING_MESSAGE <started|stopped> <name>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypingNotice {
+    pub name: String,
+    pub typing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaTransport {
+    Udp,
+    Tcp,
+}
+
+// "VOICE_CHANNEL_OFFER_MESSAGE <udp|tcp> <port> <token>", the port is on the chat
+// server's host and the token tells the server who is sending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoiceOffer {
+    pub transport: MediaTransport,
+    pub port: u16,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    Idle,
+    InVoice,
+}
+
+// "PRESENCE_MESSAGE <online|idle|voice> <name>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresenceNotice {
+    pub name: String,
+    pub presence: Presence,
+}
+
+impl KeyAnnouncement {
+    fn serialize(&self) -> String {
+        format!(
+            "{} {} {}",
+            PUBLIC_KEY_MESSAGE,
+            encode(&self.public_key),
+            self.name
+        )
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<1>(PUBLIC_KEY_MESSAGE, body)?;
+        Ok(KeyAnnouncement {
+            name,
+            public_key: decode_key("public key", fields[0])?,
+        })
+    }
+}
+
+impl SenderKeyDelivery {
+    fn serialize(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            SENDER_KEY_MESSAGE,
+            encode(&self.recipient),
+            encode(&self.sealed_key),
+            self.name
+        )
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<2>(SENDER_KEY_MESSAGE, body)?;
+        Ok(SenderKeyDelivery {
+            name,
+            recipient: decode_key("recipient", fields[0])?,
+            sealed_key: decode("sealed key", fields[1])?,
+        })
+    }
+}
+
+impl GroupPayload {
+    fn serialize(&self) -> String {
+        format!(
+            "{} {} {}",
+            GROUP_MESSAGE,
+            encode(&self.ciphertext),
+            self.name
+        )
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<1>(GROUP_MESSAGE, body)?;
+        Ok(GroupPayload {
+            name,
+            ciphertext: decode("ciphertext", fields[0])?,
+        })
+    }
+}
+
+impl DirectPayload {
+    fn serialize(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            DIRECT_MESSAGE,
+            encode(&self.recipient),
+            encode(&self.ciphertext),
+            self.name
+        )
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<2>(DIRECT_MESSAGE, body)?;
+        Ok(DirectPayload {
+            name,
+            recipient: decode_key("recipient", fields[0])?,
+            ciphertext: decode("ciphertext", fields[1])?,
+        })
+    }
+}
+
+impl TypingNotice {
+    fn serialize(&self) -> String {
+        let state = if self.typing {
+            TYPING_STARTED
+        } else {
+            TYPING_STOPPED
+        };
+        format!("{} {} {}", TYPING_MESSAGE, state, self.name)
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<1>(TYPING_MESSAGE, body)?;
+        let typing = match fields[0] {
+            TYPING_STARTED => true,
+            TYPING_STOPPED => false,
+            state => {
+                return Err(ProtocolError::InvalidField(
+                    "typing state",
+                    state.to_string(),
+                ))
+            }
+        };
+        Ok(TypingNotice { name, typing })
+    }
+}
+
+impl VoiceOffer {
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let mut fields = body.split_whitespace();
+        let mut next = |field| {
+            fields.next().ok_or(ProtocolError::MissingField(
+                VOICE_CHANNEL_OFFER_MESSAGE,
+                field,
+            ))
+        };
+        let transport = match next("transport")? {
+            "udp" => MediaTransport::Udp,
+            "tcp" => MediaTransport::Tcp,
+            transport => {
+                return Err(ProtocolError::InvalidField(
+                    "transport",
+                    transport.to_string(),
+                ))
+            }
+        };
+        let port = next("port")?;
+        let port = port
+            .parse()
+            .map_err(|_| ProtocolError::InvalidField("port", port.to_string()))?;
+        let token = next("token")?.to_string();
+        Ok(VoiceOffer {
+            transport,
+            port,
+            token,
+        })
+    }
+}
+
+impl PresenceNotice {
+    fn serialize(&self) -> String {
+        let presence = match self.presence {
+            Presence::Online => "online",
+            Presence::Idle => "idle",
+            Presence::InVoice => "voice",
+        };
+        format!("{} {} {}", PRESENCE_MESSAGE, presence, self.name)
+    }
+
+    fn parse(body: &str) -> Result<Self, ProtocolError> {
+        let (fields, name) = split_fields::<1>(PRESENCE_MESSAGE, body)?;
+        let presence = match fields[0] {
+            "online" => Presence::Online,
+            "idle" => Presence::Idle,
+            "voice" => Presence::InVoice,
+            presence => {
+                return Err(ProtocolError::InvalidField(
+                    "presence",
+                    presence.to_string(),
+                ))
+            }
+        };
+        Ok(PresenceNotice { name, presence })
+    }
+}
+
+// the plaintext inside a group message: a version line, the message id shared by
+// every member, the author's clock, the id of the message replied to, empty if none,
+// and then the text, which may hold newlines
+const CHAT_PAYLOAD_VERSION: &str = "LCMSG1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatPayload {
+    pub id: String,
+    pub sent_at: Option<u64>,
+    pub reply_to: Option<String>,
+    pub text: String,
+}
+
+impl ChatPayload {
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            CHAT_PAYLOAD_VERSION,
+            self.id,
+            self.sent_at.unwrap_or(0),
+            self.reply_to.as_deref().unwrap_or(""),
+            self.text
+        )
+    }
+
+    // older clients send "name > text"; the prefix is only stripped when it names
+    // the sender, so a message that merely contains " > " stays intact
+    pub fn parse(plaintext: &str, sender: &str, new_id: impl FnOnce() -> String) -> Self {
+        let mut fields = plaintext.splitn(5, '\n');
+        if fields.next() == Some(CHAT_PAYLOAD_VERSION) {
+            let (id, sent_at, reply_to) = (fields.next(), fields.next(), fields.next());
+            if let (Some(id), Some(sent_at), Some(reply_to), Some(text)) =
+                (id, sent_at, reply_to, fields.next())
+            {
+                return ChatPayload {
+                    id: id.to_string(),
+                    sent_at: sent_at.parse().ok().filter(|t| *t > 0),
+                    reply_to: Some(reply_to.to_string()).filter(|id| !id.is_empty()),
+                    text: text.to_string(),
+                };
+            }
+        }
+        let text = match plaintext.split_once('>') {
+            Some((name, text)) if name.trim() == sender => text.trim_start(),
+            _ => plaintext,
+        };
+        ChatPayload {
+            id: new_id(),
+            sent_at: None,
+            reply_to: None,
+            text: text.to_string(),
+        }
+    }
+}
+
+// an author changing one of their earlier messages: "LCEDIT1\n<id>\n<text>" or
+// "LCDEL1\n<id>"
+const EDIT_PAYLOAD_VERSION: &str = "LCEDIT1";
+const DELETE_PAYLOAD_VERSION: &str = "LCDEL1";
+// "LCREACT1\n<id>\n<+|->\n<emoji>", a member adding or taking back a reaction
+const REACTION_PAYLOAD_VERSION: &str = "LCREACT1";
+// longer than any emoji sequence worth showing
+const MAX_REACTION_CHARS: usize = 8;
+
+// what a group or direct message holds once decrypted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageBody {
+    Chat(ChatPayload),
+    // the new text of the message with this id
+    Edit {
+        id: String,
+        text: String,
+    },
+    Delete {
+        id: String,
+    },
+    // from whoever sent it, to the message with this id
+    Reaction {
+        id: String,
+        emoji: String,
+        added: bool,
+    },
+}
+
+impl MessageBody {
+    pub fn serialize(&self) -> String {
+        match self {
+            MessageBody::Chat(chat_payload) => chat_payload.serialize(),
+            MessageBody::Edit { id, text } => {
+                format!("{}\n{}\n{}", EDIT_PAYLOAD_VERSION, id, text)
+            }
+            MessageBody::Delete { id } => format!("{}\n{}", DELETE_PAYLOAD_VERSION, id),
+            MessageBody::Reaction { id, emoji, added } => format!(
+                "{}\n{}\n{}\n{}",
+                REACTION_PAYLOAD_VERSION,
+                id,
+                if *added { "+" } else { "-" },
+                emoji
+            ),
+        }
+    }
+
+    // anything that is not a well-formed edit, delete or reaction is read as a chat
+    // message
+    pub fn parse(plaintext: &str, sender: &str, new_id: impl FnOnce() -> String) -> Self {
+        let (version, rest) = plaintext.split_once('\n').unwrap_or((plaintext, ""));
+        match version {
+            EDIT_PAYLOAD_VERSION => {
+                if let Some((id, text)) = rest.split_once('\n') {
+                    return MessageBody::Edit {
+                        id: id.to_string(),
+                        text: text.to_string(),
+                    };
+                }
+            }
+            DELETE_PAYLOAD_VERSION if !rest.is_empty() && !rest.contains('\n') => {
+                return MessageBody::Delete {
+                    id: rest.to_string(),
+                };
+            }
+            REACTION_PAYLOAD_VERSION => {
+                let mut fields = rest.splitn(3, '\n');
+                if let (Some(id), Some(sign @ ("+" | "-")), Some(emoji)) =
+                    (fields.next(), fields.next(), fields.next())
+                {
+                    let chars = emoji.chars().count();
+                    if (1..=MAX_REACTION_CHARS).contains(&chars)
+                        && !emoji.contains(char::is_whitespace)
+                    {
+                        return MessageBody::Reaction {
+                            id: id.to_string(),
+                            emoji: emoji.to_string(),
+                            added: sign == "+",
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        MessageBody::Chat(ChatPayload::parse(plaintext, sender, new_id))
+    }
+}
+
+impl ClientFrame {
+    pub fn join_room(room_id: &str, name: &str) -> Result<Self, ProtocolError> {
+        let room_id = room_id.trim();
+        let name = name.trim();
+        if room_id.is_empty() || room_id.contains(char::is_whitespace) {
+            return Err(ProtocolError::InvalidField(
+                "room id",
+                "must be a single word".to_string(),
+            ));
+        }
+        if name.is_empty() {
+            return Err(ProtocolError::InvalidField(
+                "name",
+                "must not be empty".to_string(),
+            ));
+        }
+        Ok(ClientFrame::JoinRoom {
+            room_id: room_id.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        match self {
+            ClientFrame::JoinRoom { room_id, name } => {
+                format!("{} {} {}", JOIN_ROOM, room_id, name)
+            }
+            ClientFrame::LeaveRoom { room_id, name } => {
+                format!("{} {} {}", LEAVE_ROOM, room_id, name)
+            }
+            ClientFrame::JoinVoiceChannel { name } => {
+                format!("{} {}", JOIN_VOICE_CHANNEL_MESSAGE, name)
+            }
+            ClientFrame::LeaveVoiceChannel { name } => {
+                format!("{} {}", LEAVE_VOICE_CHANNEL_MESSAGE, name)
+            }
+            ClientFrame::ListMembers { room_id } => format!("{} {}", LIST_MEMBERS, room_id),
+            ClientFrame::PublicKey(announcement) => announcement.serialize(),
+            ClientFrame::SenderKey(delivery) => delivery.serialize(),
+            ClientFrame::GroupMessage(payload) => payload.serialize(),
+            ClientFrame::DirectMessage(payload) => payload.serialize(),
+            ClientFrame::Typing(notice) => notice.serialize(),
+            ClientFrame::Presence(notice) => notice.serialize(),
+        }
+    }
+}
+
+impl ServerFrame {
+    pub fn parse(frame: &str) -> Result<Self, ProtocolError> {
+        let (kind, body) = split_kind(frame)?;
+        match kind {
+            WELCOME_MESSAGE => Ok(ServerFrame::Welcome(body.to_string())),
+            ROOM_JOIN_SUCCESS_MESSAGE => Ok(ServerFrame::RoomJoinSuccess(body.to_string())),
+            ROOM_JOIN_FAILURE_MESSAGE => Ok(ServerFrame::RoomJoinFailure(body.to_string())),
+            NORMAL_MESSAGE => Ok(ServerFrame::NormalMessage(decode_payload(body)?)),
+            SYSTEM_MESSAGE => Ok(ServerFrame::System(body.to_string())),
+            PUBLIC_KEY_MESSAGE => Ok(ServerFrame::PublicKey(KeyAnnouncement::parse(body)?)),
+            SENDER_KEY_MESSAGE => Ok(ServerFrame::SenderKey(SenderKeyDelivery::parse(body)?)),
+            GROUP_MESSAGE => Ok(ServerFrame::GroupMessage(GroupPayload::parse(body)?)),
+            DIRECT_MESSAGE => Ok(ServerFrame::DirectMessage(DirectPayload::parse(body)?)),
+            TYPING_MESSAGE => Ok(ServerFrame::Typing(TypingNotice::parse(body)?)),
+            VOICE_CHANNEL_OFFER_MESSAGE => Ok(ServerFrame::VoiceOffer(VoiceOffer::parse(body)?)),
+            PRESENCE_MESSAGE => Ok(ServerFrame::Presence(PresenceNotice::parse(body)?)),
+            MEMBER_LIST_MESSAGE => Ok(ServerFrame::MemberList(
+                body.lines()
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            MEMBER_JOINED_MESSAGE => Ok(ServerFrame::MemberJoined(required_name(
+                MEMBER_JOINED_MESSAGE,
+                body,
+            )?)),
+            MEMBER_LEFT_MESSAGE => Ok(ServerFrame::MemberLeft(required_name(
+                MEMBER_LEFT_MESSAGE,
+                body,
+            )?)),
+            _ => Err(ProtocolError::UnknownKind(kind.to_string())),
+        }
+    }
+}
+
+fn split_kind(frame: &str) -> Result<(&str, &str), ProtocolError> {
+    let frame = frame.trim();
+    if frame.is_empty() {
+        return Err(ProtocolError::Empty);
+    }
+    Ok(match frame.split_once(' ') {
+        Some((kind, body)) => (kind, body.trim()),
+        None => (frame, ""),
+    })
+}
+
+// splits N space separated fields off the front, the rest of the body is the name
+fn split_fields<'a, const N: usize>(
+    kind: &'static str,
+    body: &'a str,
+) -> Result<([&'a str; N], String), ProtocolError> {
+    let mut fields = [""; N];
+    let mut rest = body;
+    for field in fields.iter_mut() {
+        let (value, tail) = rest
+            .split_once(' ')
+            .ok_or(ProtocolError::MissingField(kind, "name"))?;
+        *field = value;
+        rest = tail.trim_start();
+    }
+    if rest.is_empty() {
+        return Err(ProtocolError::MissingField(kind, "name"));
+    }
+    Ok((fields, rest.to_string()))
+}
+
+fn required_name(kind: &'static str, body: &str) -> Result<String, ProtocolError> {
+    if body.is_empty() {
+        return Err(ProtocolError::MissingField(kind, "name"));
+    }
+    Ok(body.to_string())
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::prelude::BASE64_STANDARD.encode(bytes)
+}
+
+fn decode(field: &'static str, value: &str) -> Result<Vec<u8>, ProtocolError> {
+    base64::prelude::BASE64_STANDARD
+        .decode(value)
+        .map_err(|e| ProtocolError::InvalidField(field, e.to_string()))
+}
+
+fn decode_key(field: &'static str, value: &str) -> Result<[u8; 32], ProtocolError> {
+    decode(field, value)?
+        .try_into()
+        .map_err(|_| ProtocolError::InvalidField(field, "expected 32 bytes".to_string()))
+}
+
+fn decode_payload(body: &str) -> Result<Vec<u8>, ProtocolError> {
+    if body.is_empty() {
+        return Err(ProtocolError::MissingField(NORMAL_MESSAGE, "payload"));
+    }
+    decode("payload", body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_client_frames() {
+        let join = ClientFrame::join_room(" lobby ", " Mary Ann ").unwrap();
+        assert_eq!(join.serialize(), "JOIN_ROOM lobby Mary Ann");
+        assert!(ClientFrame::join_room("two words", "bob").is_err());
+        assert!(ClientFrame::join_room("lobby", "  ").is_err());
+        let voice = ClientFrame::JoinVoiceChannel {
+            name: "bob".to_string(),
+        };
+        assert_eq!(voice.serialize(), "JOIN_VOICE_CHANNEL_MESSAGE bob");
+    }
+
+    #[test]
+    fn parses_server_frames() {
+        assert_eq!(
+            ServerFrame::parse("WELCOME_MESSAGE hello there\n"),
+            Ok(ServerFrame::Welcome("hello there".to_string()))
+        );
+        assert_eq!(
+            ServerFrame::parse("ROOM_JOIN_SUCCESS_MESSAGE joined lobby"),
+            Ok(ServerFrame::RoomJoinSuccess("joined lobby".to_string()))
+        );
+        assert_eq!(
+            ServerFrame::parse("ROOM_JOIN_FAILURE_MESSAGE name taken"),
+            Ok(ServerFrame::RoomJoinFailure("name taken".to_string()))
+        );
+        assert_eq!(
+            ServerFrame::parse("SYSTEM_MESSAGE"),
+            Ok(ServerFrame::System(String::new()))
+        );
+        assert_eq!(
+            ServerFrame::parse("NORMAL_MESSAGE AQID"),
+            Ok(ServerFrame::NormalMessage(vec![1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert_eq!(ServerFrame::parse(" \n"), Err(ProtocolError::Empty));
+        assert_eq!(
+            ServerFrame::parse("DANCE now"),
+            Err(ProtocolError::UnknownKind("DANCE".to_string()))
+        );
+        assert_eq!(
+            ServerFrame::parse("NORMAL_MESSAGE"),
+            Err(ProtocolError::MissingField("NORMAL_MESSAGE", "payload"))
+        );
+        assert!(matches!(
+            ServerFrame::parse("NORMAL_MESSAGE not*base64"),
+            Err(ProtocolError::InvalidField("payload", _))
+        ));
+    }
+
+    #[test]
+    fn chat_payloads_keep_the_text_intact() {
+        let mut payload = ChatPayload {
+            id: "0123456789abcdef".to_string(),
+            sent_at: Some(1_700_000_000),
+            reply_to: None,
+            text: "a > b\nand more".to_string(),
+        };
+        let parsed = ChatPayload::parse(&payload.serialize(), "alice", || unreachable!());
+        assert_eq!(parsed, payload);
+        payload.reply_to = Some("fedcba9876543210".to_string());
+        let parsed = ChatPayload::parse(&payload.serialize(), "alice", || unreachable!());
+        assert_eq!(parsed, payload);
+        assert!(payload
+            .serialize()
+            .starts_with("LCMSG1\n0123456789abcdef\n1700000000\nfedcba9876543210\n"));
+
+        // "name > text" from older clients
+        let legacy = ChatPayload::parse("alice > a > b", "alice", || "id".to_string());
+        assert_eq!(legacy.text, "a > b");
+        assert_eq!(legacy.sent_at, None);
+        let unprefixed = ChatPayload::parse("a > b", "alice", || "id".to_string());
+        assert_eq!(unprefixed.text, "a > b");
+    }
+
+    #[test]
+    fn message_bodies_round_trip() {
+        let bodies = [
+            MessageBody::Edit {
+                id: "0123456789abcdef".to_string(),
+                text: "fixed\ntypo".to_string(),
+            },
+            MessageBody::Delete {
+                id: "0123456789abcdef".to_string(),
+            },
+            MessageBody::Reaction {
+                id: "0123456789abcdef".to_string(),
+                emoji: "👍🏽".to_string(),
+                added: false,
+            },
+        ];
+        for body in bodies {
+            let parsed = MessageBody::parse(&body.serialize(), "alice", || unreachable!());
+            assert_eq!(parsed, body);
+        }
+        // chat messages that merely look like one stay chat messages
+        assert!(matches!(
+            MessageBody::parse("LCDEL1\nab\ncd", "alice", || "id".to_string()),
+            MessageBody::Chat(_)
+        ));
+        assert!(matches!(
+            MessageBody::parse("LCREACT1\nab\n+\nnot an emoji", "alice", || {
+                "id".to_string()
+            }),
+            MessageBody::Chat(_)
+        ));
+    }
+
+    #[test]
+    fn typing_notices_round_trip() {
+        let notice = TypingNotice {
+            name: "Mary Ann".to_string(),
+            typing: true,
+        };
+        let frame = ClientFrame::Typing(notice.clone()).serialize();
+        assert_eq!(frame, "TYPING_MESSAGE started Mary Ann");
+        assert_eq!(ServerFrame::parse(&frame), Ok(ServerFrame::Typing(notice)));
+        assert!(ServerFrame::parse("TYPING_MESSAGE maybe bob").is_err());
+    }
+
+    #[test]
+    fn parses_voice_offers() {
+        assert_eq!(
+            ServerFrame::parse("VOICE_CHANNEL_OFFER_MESSAGE udp 9000 c2VjcmV0"),
+            Ok(ServerFrame::VoiceOffer(VoiceOffer {
+                transport: MediaTransport::Udp,
+                port: 9000,
+                token: "c2VjcmV0".to_string(),
+            }))
+        );
+        assert!(ServerFrame::parse("VOICE_CHANNEL_OFFER_MESSAGE sctp 9000 t").is_err());
+        assert!(ServerFrame::parse("VOICE_CHANNEL_OFFER_MESSAGE tcp 99999 t").is_err());
+        assert!(ServerFrame::parse("VOICE_CHANNEL_OFFER_MESSAGE tcp 9000").is_err());
+    }
+
+    #[test]
+    fn direct_messages_round_trip() {
+        let payload = DirectPayload {
+            name: "Mary Ann".to_string(),
+            recipient: [7; 32],
+            ciphertext: vec![1, 2, 3],
+        };
+        let frame = ClientFrame::DirectMessage(payload.clone()).serialize();
+        assert_eq!(
+            ServerFrame::parse(&frame),
+            Ok(ServerFrame::DirectMessage(payload))
+        );
+        assert!(ServerFrame::parse("DIRECT_MESSAGE AQID bob").is_err());
+    }
+
+    #[test]
+    fn parses_membership_frames() {
+        assert_eq!(
+            ServerFrame::parse("MEMBER_LIST_MESSAGE alice\nMary Ann\n"),
+            Ok(ServerFrame::MemberList(vec![
+                "alice".to_string(),
+                "Mary Ann".to_string()
+            ]))
+        );
+        assert_eq!(
+            ServerFrame::parse("MEMBER_LIST_MESSAGE"),
+            Ok(ServerFrame::MemberList(vec![]))
+        );
+        assert_eq!(
+            ServerFrame::parse("MEMBER_LEFT_MESSAGE Mary Ann"),
+            Ok(ServerFrame::MemberLeft("Mary Ann".to_string()))
+        );
+        assert!(ServerFrame::parse("MEMBER_JOINED_MESSAGE").is_err());
+
+        let notice = PresenceNotice {
+            name: "bob".to_string(),
+            presence: Presence::InVoice,
+        };
+        let frame = ClientFrame::Presence(notice.clone()).serialize();
+        assert_eq!(frame, "PRESENCE_MESSAGE voice bob");
+        assert_eq!(
+            ServerFrame::parse(&frame),
+            Ok(ServerFrame::Presence(notice))
+        );
+    }
+}
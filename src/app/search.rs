@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const MAX_HITS: usize = 100;
+
+struct IndexedMessage {
+    // position in the conversation
+    position: usize,
+    timestamp: u64,
+    name: String,
+}
+
+// inverted index over the words of a room's messages, grown one message at a time
+#[derive(Default)]
+pub struct SearchIndex {
+    messages: Vec<IndexedMessage>,
+    // word -> ids into `messages`, ascending
+    postings: BTreeMap<String, Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchHit {
+    pub position: usize,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub text: String,
+    pub sender: String,
+    // unix seconds, both ends inclusive
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl SearchQuery {
+    fn is_empty(&self) -> bool {
+        self.text.trim().is_empty()
+            && self.sender.trim().is_empty()
+            && self.from.is_none()
+            && self.to.is_none()
+    }
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        SearchIndex::default()
+    }
+
+    pub fn add(&mut self, position: usize, timestamp: u64, name: &str, content: &str) {
+        let id = self.messages.len();
+        self.messages.push(IndexedMessage {
+            position,
+            timestamp,
+            name: name.to_lowercase(),
+        });
+        let words: BTreeSet<String> = words(content).collect();
+        for word in words {
+            self.postings.entry(word).or_default().push(id);
+        }
+    }
+
+    // matching messages, newest first; every query word has to start a word of the
+    // message, so "hel" finds "hello"
+    pub fn search(&self, query: &SearchQuery) -> Vec<SearchHit> {
+        if query.is_empty() {
+            return vec![];
+        }
+        let mut candidates: Option<BTreeSet<usize>> = None;
+        for term in words(&query.text) {
+            let matches: BTreeSet<usize> = self
+                .postings
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(&term))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&matches).copied().collect(),
+                None => matches,
+            });
+        }
+
+        let sender = query.sender.trim().to_lowercase();
+        let matches = |message: &IndexedMessage| {
+            message.name.contains(&sender)
+                && query.from.is_none_or(|from| message.timestamp >= from)
+                && query.to.is_none_or(|to| message.timestamp <= to)
+        };
+        let ids: Box<dyn Iterator<Item = usize>> = match candidates {
+            Some(c) => Box::new(c.into_iter().rev()),
+            None => Box::new((0..self.messages.len()).rev()),
+        };
+        ids.map(|id| &self.messages[id])
+            .filter(|message| matches(message))
+            .map(|message| SearchHit {
+                position: message.position,
+                timestamp: message.timestamp,
+            })
+            .take(MAX_HITS)
+            .collect()
+    }
+}
+
+// up to `width` characters of the content around the first query word found in it
+pub fn snippet(content: &str, query_text: &str, width: usize) -> String {
+    let lowercase = content.to_lowercase();
+    let start = words(query_text)
+        .find_map(|word| lowercase.find(&word))
+        .map(|byte| lowercase[..byte].chars().count())
+        .unwrap_or(0);
+    let chars: Vec<char> = content.chars().collect();
+    let from = start
+        .saturating_sub(width / 3)
+        .min(chars.len().saturating_sub(width));
+    let to = (from + width).min(chars.len());
+    let mut snippet: String = chars[from..to].iter().collect();
+    if from > 0 {
+        snippet.insert(0, '…');
+    }
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn words(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::timestamp::{end_of_day, parse_date};
+
+    fn index() -> SearchIndex {
+        let day = |d: &str| parse_date(d).unwrap();
+        let mut index = SearchIndex::new();
+        index.add(0, day("2024-03-01"), "alice", "Hello there, Bob!");
+        // position 1 is a system message that was never indexed
+        index.add(2, day("2024-03-02"), "Bob", "hello alice, lunch at noon?");
+        index.add(3, day("2024-03-05"), "alice", "Lunch sounds good");
+        index
+    }
+
+    fn positions(index: &SearchIndex, query: &SearchQuery) -> Vec<usize> {
+        index.search(query).iter().map(|hit| hit.position).collect()
+    }
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            ..SearchQuery::default()
+        }
+    }
+
+    #[test]
+    fn finds_words_by_prefix_newest_first() {
+        let index = index();
+        assert_eq!(positions(&index, &query("hello")), vec![2, 0]);
+        assert_eq!(positions(&index, &query("LUN")), vec![3, 2]);
+        assert_eq!(positions(&index, &query("hello lunch")), vec![2]);
+        assert_eq!(positions(&index, &query("dinner")), Vec::<usize>::new());
+        assert_eq!(positions(&index, &query("")), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn filters_by_sender_and_date() {
+        let index = index();
+        let by_alice = SearchQuery {
+            sender: "ALI".to_string(),
+            ..query("lunch")
+        };
+        assert_eq!(positions(&index, &by_alice), vec![3]);
+
+        let early_march = SearchQuery {
+            from: parse_date("2024-03-02"),
+            to: parse_date("2024-03-04").map(end_of_day),
+            ..SearchQuery::default()
+        };
+        assert_eq!(positions(&index, &early_march), vec![2]);
+    }
+
+    #[test]
+    fn snippets_center_on_the_match() {
+        let content = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(snippet(content, "lazy", 12), "…the lazy dog");
+        assert_eq!(snippet(content, "quick", 100), content);
+        assert_eq!(snippet(content, "", 9), "the quick…");
+    }
+}
@@ -1,37 +1,203 @@
-use core::str;
+use core::{fmt, str};
 
 use aes::{
-    cipher::{generic_array::GenericArray, typenum, BlockDecrypt, BlockEncrypt, KeyInit},
+    cipher::{generic_array::GenericArray, typenum, BlockDecrypt, KeyInit},
     Aes128,
 };
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes128Gcm, Nonce,
+};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 const BLOCK_SIZE: usize = 16;
 
-pub fn encrypt(message: &str, key: &[u8; 16]) -> Vec<u8> {
-    let cipher = Aes128::new(GenericArray::from_slice(key));
-    let mut data = message.as_bytes().to_vec();
+// envelope layout: magic, version, nonce, ciphertext with the gcm tag appended
+const ENVELOPE_MAGIC: &[u8] = b"LC";
+pub const ENVELOPE_VERSION: u8 = 1;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = ENVELOPE_MAGIC.len() + 1 + NONCE_SIZE;
 
-    let pad_len = BLOCK_SIZE - (data.len() % BLOCK_SIZE);
-    data.extend(std::iter::repeat(pad_len as u8).take(pad_len));
+const ROOM_SALT_PREFIX: &str = "letschat-room:";
+const PAIRWISE_KEY_INFO: &[u8] = b"letschat-pairwise-key";
 
-    let mut blocks: Vec<GenericArray<u8, typenum::U16>> = data
-        .chunks_exact(BLOCK_SIZE)
-        .map(|chunk| *GenericArray::from_slice(chunk))
-        .collect();
+#[derive(Clone)]
+pub struct RoomKey([u8; 16]);
+
+// keep key material out of debug logs of the messages carrying it
+impl fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RoomKey(..)")
+    }
+}
+
+impl RoomKey {
+    pub fn bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+// argon2id over the passphrase, salted with the room id so equal passphrases give
+// different keys in different rooms; this is slow on purpose, keep it off the ui thread
+pub fn derive_room_key(passphrase: &str, room_id: &str) -> RoomKey {
+    let salt = format!("{}{}", ROOM_SALT_PREFIX, room_id);
+    RoomKey(derive_key(passphrase, salt.as_bytes()))
+}
 
-    cipher.encrypt_blocks(&mut blocks);
+// same argon2id derivation for keys that never leave this machine; the salt must
+// be at least 8 bytes
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .expect("salt and output length are within argon2 limits");
+    key
+}
+
+// long-term x25519 keypair identifying this client to the other room members
+#[derive(Clone)]
+pub struct IdentityKeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl IdentityKeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        IdentityKeyPair { secret, public }
+    }
+
+    // restores a keypair saved with `secret_bytes`
+    pub fn from_secret_bytes(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        IdentityKeyPair { secret, public }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
 
-    blocks
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    // both ends of a pair arrive at the same key; mixing in the room key means a
+    // member also has to know the passphrase, not just hold a keypair
+    pub fn pairwise_key(&self, peer_public_key: &[u8; 32], room_key: &RoomKey) -> [u8; 16] {
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(*peer_public_key));
+        let mut key = [0u8; 16];
+        Hkdf::<Sha256>::new(Some(room_key.bytes()), shared_secret.as_bytes())
+            .expand(PAIRWISE_KEY_INFO, &mut key)
+            .expect("16 bytes is a valid hkdf-sha256 output length");
+        key
+    }
+}
+
+// short hex id of an identity key, shown to tell members with the same name apart
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    public_key[..8]
         .iter()
-        .flat_map(|block| block.iter().copied())
+        .map(|b| format!("{:02x}", b))
         .collect()
 }
 
-pub fn decrypt(msg: Vec<u8>, key: &[u8; 16]) -> String {
+pub fn generate_sender_key() -> [u8; 16] {
+    Aes128Gcm::generate_key(OsRng).into()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptError {
+    Truncated,
+    UnsupportedVersion(u8),
+    Authentication,
+    InvalidPadding,
+    InvalidUtf8,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::Truncated => write!(f, "ciphertext is truncated"),
+            DecryptError::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            DecryptError::Authentication => write!(f, "ciphertext failed authentication"),
+            DecryptError::InvalidPadding => write!(f, "invalid padding"),
+            DecryptError::InvalidUtf8 => write!(f, "plaintext is not valid utf-8"),
+        }
+    }
+}
+
+pub fn encrypt(message: &str, key: &[u8; 16]) -> Vec<u8> {
+    seal(message.as_bytes(), key)
+}
+
+pub fn seal(plaintext: &[u8], key: &[u8; 16]) -> Vec<u8> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .expect("aes-gcm encryption does not fail for in-memory buffers");
+
+    let mut envelope = Vec::with_capacity(HEADER_SIZE + ciphertext.len());
+    envelope.extend_from_slice(ENVELOPE_MAGIC);
+    envelope.push(ENVELOPE_VERSION);
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&ciphertext);
+    envelope
+}
+
+pub fn decrypt(msg: &[u8], key: &[u8; 16]) -> Result<String, DecryptError> {
+    // anything with a current envelope header is sealed, so a failed tag is final;
+    // only input without one is taken for an old-style ciphertext
+    if is_envelope(msg) {
+        return open_text(msg, key);
+    }
+    decrypt_legacy(msg, key)
+}
+
+fn is_envelope(msg: &[u8]) -> bool {
+    msg.starts_with(ENVELOPE_MAGIC) && msg.get(ENVELOPE_MAGIC.len()) == Some(&ENVELOPE_VERSION)
+}
+
+// like `decrypt` without the legacy fallback, for messages that are always sealed
+pub fn open_text(msg: &[u8], key: &[u8; 16]) -> Result<String, DecryptError> {
+    String::from_utf8(open(msg, key)?).map_err(|_| DecryptError::InvalidUtf8)
+}
+
+// opens an envelope produced by `seal`
+pub fn open(msg: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    if !msg.starts_with(ENVELOPE_MAGIC) || msg.len() <= ENVELOPE_MAGIC.len() {
+        return Err(DecryptError::Truncated);
+    }
+    let version = msg[ENVELOPE_MAGIC.len()];
+    if version != ENVELOPE_VERSION {
+        return Err(DecryptError::UnsupportedVersion(version));
+    }
+    if msg.len() < HEADER_SIZE {
+        return Err(DecryptError::Truncated);
+    }
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(key));
+    let nonce = Nonce::from_slice(&msg[ENVELOPE_MAGIC.len() + 1..HEADER_SIZE]);
+    cipher
+        .decrypt(nonce, &msg[HEADER_SIZE..])
+        .map_err(|_| DecryptError::Authentication)
+}
+
+// AES-128-ECB messages from clients that predate the envelope
+fn decrypt_legacy(msg: &[u8], key: &[u8; 16]) -> Result<String, DecryptError> {
+    if msg.is_empty() || !msg.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(DecryptError::Truncated);
+    }
     let cipher = Aes128::new(GenericArray::from_slice(key));
 
     let mut blocks: Vec<GenericArray<u8, typenum::U16>> = msg
-        .chunks_exact(16)
+        .chunks_exact(BLOCK_SIZE)
         .map(|chunk| *GenericArray::from_slice(chunk))
         .collect();
     cipher.decrypt_blocks(&mut blocks);
@@ -39,8 +205,40 @@ pub fn decrypt(msg: Vec<u8>, key: &[u8; 16]) -> String {
         .iter()
         .flat_map(|block| block.iter().copied())
         .collect();
-     
-    let len = bytes.len() - *bytes.last().unwrap() as usize;
 
-    str::from_utf8(&bytes[..len]).unwrap().to_string()
+    let pad_len = *bytes.last().unwrap() as usize;
+    let padding_ok = pad_len > 0
+        && pad_len <= BLOCK_SIZE
+        && bytes[bytes.len() - pad_len..]
+            .iter()
+            .all(|b| *b as usize == pad_len);
+    if !padding_ok {
+        return Err(DecryptError::InvalidPadding);
+    }
+    let len = bytes.len() - pad_len;
+
+    str::from_utf8(&bytes[..len])
+        .map(|s| s.to_string())
+        .map_err(|_| DecryptError::InvalidUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_envelopes_fail_authentication() {
+        let key = [7u8; 16];
+        // a whole number of blocks, so it could also pass for an old-style ciphertext
+        let mut envelope = encrypt("meet at noon, ok?", &key);
+        assert!(envelope.len().is_multiple_of(BLOCK_SIZE));
+        assert_eq!(decrypt(&envelope, &key).unwrap(), "meet at noon, ok?");
+        assert_eq!(
+            decrypt(&envelope, &[8u8; 16]),
+            Err(DecryptError::Authentication)
+        );
+        let last = envelope.len() - 1;
+        envelope[last] ^= 1;
+        assert_eq!(decrypt(&envelope, &key), Err(DecryptError::Authentication));
+    }
 }
@@ -0,0 +1,385 @@
+use std::{future::Future, time::Duration};
+
+use iced::futures::{
+    channel::mpsc::{SendError, Sender},
+    SinkExt,
+};
+use rand::Rng;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use super::config::ServerAddress;
+use super::connection::{self, Connection, ConnectionError};
+use super::protocol::{ClientFrame, ProtocolError, ServerFrame};
+
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_CAP: Duration = Duration::from_secs(30);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Reconnecting { attempt: u32, retry_in: Duration },
+    Offline(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Frame(Result<ServerFrame, ProtocolError>),
+    Status(ConnectionStatus),
+    // the frame carrying this chat message was taken by the server
+    Delivered(String),
+}
+
+// a frame to write, with the id of the chat message it carries, if any
+#[derive(Debug)]
+pub struct Outgoing {
+    frame: ClientFrame,
+    message_id: Option<String>,
+}
+
+// owns a running session task; dropping the handle aborts the task on the spot,
+// `close` lets it flush queued frames first
+pub struct SessionHandle {
+    outgoing: Option<UnboundedSender<Outgoing>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl SessionHandle {
+    pub fn spawn(
+        connection: Option<Connection>,
+        server_address: ServerAddress,
+        join_frame: ClientFrame,
+        events: Sender<SessionEvent>,
+    ) -> Self {
+        let (outgoing, outgoing_rx) = unbounded_channel();
+        let task = tokio::spawn(run(
+            connection,
+            server_address,
+            join_frame,
+            outgoing_rx,
+            events,
+        ));
+        SessionHandle {
+            outgoing: Some(outgoing),
+            task: Some(task),
+        }
+    }
+
+    // false once the task has stopped, e.g. after reconnecting gave up
+    pub fn send(&self, frame: ClientFrame) -> bool {
+        self.queue(Outgoing {
+            frame,
+            message_id: None,
+        })
+    }
+
+    // like `send`, and reports `SessionEvent::Delivered` once the frame is written
+    pub fn send_message(&self, frame: ClientFrame, message_id: String) -> bool {
+        self.queue(Outgoing {
+            frame,
+            message_id: Some(message_id),
+        })
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> bool {
+        match &self.outgoing {
+            Some(sender) => sender.send(outgoing).is_ok(),
+            None => false,
+        }
+    }
+
+    // resolves once the task has written everything queued and closed the socket,
+    // or has been aborted for taking longer than CLOSE_TIMEOUT
+    pub fn close(mut self) -> impl Future<Output = ()> + Send + 'static {
+        self.outgoing = None;
+        let task = self.task.take();
+        async move {
+            if let Some(task) = task {
+                let abort_handle = task.abort_handle();
+                if tokio::time::timeout(CLOSE_TIMEOUT, task).await.is_err() {
+                    abort_handle.abort();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+// exponential backoff with jitter: somewhere between half and all of base * 2^attempt,
+// so clients dropped by the same outage do not all come back at once
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let delay = BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(BACKOFF_CAP);
+    let half = delay / 2;
+    half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+// drives one joined room: forwards incoming frames as events, writes outgoing frames
+// in order, and rejoins with the same join frame whenever the connection drops.
+// returns once the outgoing sender is dropped, the event receiver is gone, or
+// reconnecting gives up; a `None` connection starts straight with a reconnect
+pub async fn run(
+    connection: Option<Connection>,
+    server_address: ServerAddress,
+    join_frame: ClientFrame,
+    mut outgoing: UnboundedReceiver<Outgoing>,
+    mut events: Sender<SessionEvent>,
+) {
+    let mut connection = connection;
+    // a frame whose write failed goes out again first after reconnecting
+    let mut unsent: Option<Outgoing> = None;
+    loop {
+        let current = match connection.take() {
+            Some(c) => c,
+            None => match reconnect(&server_address, &join_frame, &mut events).await {
+                Some(c) => c,
+                None => return,
+            },
+        };
+
+        if let Some(next) = unsent.take() {
+            if current.send(&next.frame).await.is_err() {
+                unsent = Some(next);
+                continue;
+            }
+            if report_delivered(next, &mut events).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                frame = current.recv() => {
+                    let event = match frame {
+                        Ok(frame) => SessionEvent::Frame(Ok(frame)),
+                        Err(ConnectionError::Protocol(e)) => SessionEvent::Frame(Err(e)),
+                        Err(e) => {
+                            println!("Connection lost: {}", e);
+                            break;
+                        }
+                    };
+                    if events.send(event).await.is_err() {
+                        return;
+                    }
+                }
+                next = outgoing.recv() => match next {
+                    Some(next) => {
+                        if let Err(e) = current.send(&next.frame).await {
+                            println!("Connection lost: {}", e);
+                            unsent = Some(next);
+                            break;
+                        }
+                        if report_delivered(next, &mut events).await.is_err() {
+                            return;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        }
+    }
+}
+
+async fn report_delivered(
+    written: Outgoing,
+    events: &mut Sender<SessionEvent>,
+) -> Result<(), SendError> {
+    match written.message_id {
+        Some(id) => events.send(SessionEvent::Delivered(id)).await,
+        None => Ok(()),
+    }
+}
+
+async fn reconnect(
+    server_address: &ServerAddress,
+    join_frame: &ClientFrame,
+    events: &mut Sender<SessionEvent>,
+) -> Option<Connection> {
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        let retry_in = backoff_delay(attempt);
+        let status = ConnectionStatus::Reconnecting { attempt, retry_in };
+        events.send(SessionEvent::Status(status)).await.ok()?;
+        tokio::time::sleep(retry_in).await;
+
+        let Ok((connection, _greeting)) = connection::connect(server_address.clone()).await else {
+            continue;
+        };
+        match connection::join_room(connection.clone(), join_frame.clone()).await {
+            Ok(ServerFrame::RoomJoinSuccess(_)) => {
+                let status = ConnectionStatus::Connected;
+                events.send(SessionEvent::Status(status)).await.ok()?;
+                return Some(connection);
+            }
+            Ok(ServerFrame::RoomJoinFailure(reason)) => {
+                let status = ConnectionStatus::Offline(reason);
+                let _ = events.send(SessionEvent::Status(status)).await;
+                return None;
+            }
+            _ => continue,
+        }
+    }
+    let status =
+        ConnectionStatus::Offline(format!("gave up after {} attempts", MAX_RECONNECT_ATTEMPTS));
+    let _ = events.send(SessionEvent::Status(status)).await;
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use iced::futures::{channel::mpsc, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc::{unbounded_channel, UnboundedSender},
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::app::codec::{self, FrameDecoder};
+
+    // serves one client at a time: greets, accepts JOIN_ROOM, reports every frame it
+    // receives and acks it; aborting the task kills listener and socket together
+    async fn start_server(port: u16, received: UnboundedSender<String>) -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            loop {
+                let (mut tcp_stream, _) = listener.accept().await.unwrap();
+                let _ = tcp_stream
+                    .write_all(&codec::encode(b"WELCOME_MESSAGE hi"))
+                    .await;
+                let mut decoder = FrameDecoder::new();
+                let mut buf = [0u8; 1024];
+                'client: loop {
+                    match tcp_stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(bytes_read) => decoder.push(&buf[..bytes_read]),
+                    }
+                    while let Some(frame) = decoder.next_frame().unwrap() {
+                        let frame = String::from_utf8(frame).unwrap();
+                        let reply: &[u8] = if frame.starts_with("JOIN_ROOM") {
+                            b"ROOM_JOIN_SUCCESS_MESSAGE Room ID 1"
+                        } else {
+                            b"SYSTEM_MESSAGE got it"
+                        };
+                        let _ = received.send(frame);
+                        if tcp_stream.write_all(&codec::encode(reply)).await.is_err() {
+                            break 'client;
+                        }
+                    }
+                }
+            }
+        });
+        (port, handle)
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<SessionEvent>) -> SessionEvent {
+        timeout(Duration::from_secs(20), events.next())
+            .await
+            .expect("no session event")
+            .expect("session ended")
+    }
+
+    #[tokio::test]
+    async fn rejoins_after_the_server_restarts() {
+        let (received_sx, mut received) = unbounded_channel();
+        let (port, server) = start_server(0, received_sx.clone()).await;
+        let server_address = ServerAddress {
+            host: "127.0.0.1".to_string(),
+            port,
+        };
+        let join_frame = ClientFrame::join_room("1", "alice").unwrap();
+
+        let (connection, _) = connection::connect(server_address.clone()).await.unwrap();
+        connection::join_room(connection.clone(), join_frame.clone())
+            .await
+            .unwrap();
+        assert_eq!(received.recv().await.unwrap(), "JOIN_ROOM 1 alice");
+
+        let (outgoing, outgoing_rx) = unbounded_channel();
+        let (events_sx, mut events) = mpsc::channel(100);
+        let session = tokio::spawn(run(
+            Some(connection),
+            server_address,
+            join_frame,
+            outgoing_rx,
+            events_sx,
+        ));
+
+        server.abort();
+        let _ = server.await;
+        match next_event(&mut events).await {
+            SessionEvent::Status(ConnectionStatus::Reconnecting { attempt: 1, .. }) => {}
+            other => panic!("expected a reconnect attempt, got {:?}", other),
+        }
+
+        let (_, server) = start_server(port, received_sx).await;
+        loop {
+            match next_event(&mut events).await {
+                SessionEvent::Status(ConnectionStatus::Connected) => break,
+                SessionEvent::Status(ConnectionStatus::Reconnecting { .. }) => {}
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        assert_eq!(received.recv().await.unwrap(), "JOIN_ROOM 1 alice");
+
+        // the new connection carries traffic both ways
+        let frame = ClientFrame::JoinVoiceChannel {
+            name: "alice".to_string(),
+        };
+        outgoing
+            .send(Outgoing {
+                frame,
+                message_id: Some("m1".to_string()),
+            })
+            .unwrap();
+        assert_eq!(
+            received.recv().await.unwrap(),
+            "JOIN_VOICE_CHANNEL_MESSAGE alice"
+        );
+        match next_event(&mut events).await {
+            SessionEvent::Delivered(id) => assert_eq!(id, "m1"),
+            other => panic!("expected a delivery report, got {:?}", other),
+        }
+        match next_event(&mut events).await {
+            SessionEvent::Frame(Ok(ServerFrame::System(text))) => assert_eq!(text, "got it"),
+            other => panic!("expected the server reply, got {:?}", other),
+        }
+
+        // dropping the outgoing sender ends the session
+        drop(outgoing);
+        timeout(Duration::from_secs(5), session)
+            .await
+            .unwrap()
+            .unwrap();
+        server.abort();
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        for attempt in 1..=MAX_RECONNECT_ATTEMPTS + 5 {
+            let ceiling = BACKOFF_BASE
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(BACKOFF_CAP);
+            let delay = backoff_delay(attempt);
+            assert!(
+                delay >= ceiling / 2 && delay <= ceiling,
+                "attempt {}",
+                attempt
+            );
+        }
+    }
+}
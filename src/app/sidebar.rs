@@ -0,0 +1,96 @@
+use iced::{
+    advanced::graphics::core::font,
+    widget::{button, column, container, text, Column},
+    Element, Font, Length,
+};
+
+use super::chat::RoomRef;
+
+pub struct SidebarEntry {
+    pub room: RoomRef,
+    pub unread: usize,
+    pub selected: bool,
+}
+
+// a conversation with one member, carried by the room both are in
+pub struct DirectEntry {
+    pub room: RoomRef,
+    pub peer: String,
+    pub unread: usize,
+    pub selected: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum SidebarMessage {
+    SelectRoom(RoomRef),
+    SelectDirect(RoomRef, String),
+    JoinAnotherRoom,
+}
+
+fn with_unread(label: &str, unread: usize) -> String {
+    if unread > 0 {
+        format!("{} ({})", label, unread)
+    } else {
+        label.to_string()
+    }
+}
+
+fn style(selected: bool) -> fn(&iced::Theme, button::Status) -> button::Style {
+    if selected {
+        button::primary
+    } else {
+        button::secondary
+    }
+}
+
+pub fn view<'a>(
+    entries: Vec<SidebarEntry>,
+    direct_entries: Vec<DirectEntry>,
+) -> Element<'a, SidebarMessage> {
+    let mut title_font = Font::with_name("clash-grotesk-variable");
+    title_font.weight = font::Weight::Bold;
+    let title: Element<SidebarMessage> = text("Rooms").size(20).font(title_font).into();
+
+    let room_buttons = entries
+        .into_iter()
+        .map(|entry| {
+            let label = with_unread(&entry.room.room_id, entry.unread);
+            // the same room can be open under two names
+            let server_text = text(format!("{}@{}", entry.room.name, entry.room.server)).size(12);
+            button(column![text(label), server_text])
+                .on_press(SidebarMessage::SelectRoom(entry.room))
+                .width(Length::Fill)
+                .style(style(entry.selected))
+                .into()
+        })
+        .collect::<Vec<Element<SidebarMessage>>>();
+
+    let join_btn: Element<SidebarMessage> = button("Join another room")
+        .on_press(SidebarMessage::JoinAnotherRoom)
+        .width(Length::Fill)
+        .into();
+
+    let mut content = column![title, Column::from_vec(room_buttons).spacing(5), join_btn];
+    if !direct_entries.is_empty() {
+        let direct_buttons = direct_entries
+            .into_iter()
+            .map(|entry| {
+                let label = with_unread(&entry.peer, entry.unread);
+                let room_text = text(format!("in {}", entry.room.room_id)).size(12);
+                button(column![text(label), room_text])
+                    .on_press(SidebarMessage::SelectDirect(entry.room, entry.peer))
+                    .width(Length::Fill)
+                    .style(style(entry.selected))
+                    .into()
+            })
+            .collect::<Vec<Element<SidebarMessage>>>();
+        content = content
+            .push(text("Direct messages").size(20).font(title_font))
+            .push(Column::from_vec(direct_buttons).spacing(5));
+    }
+
+    container(content.spacing(10).padding(20))
+        .width(220)
+        .height(Length::Fill)
+        .into()
+}
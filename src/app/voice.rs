@@ -0,0 +1,534 @@
+// the voice channel: an `AudioSource` hands over 20 ms frames which are encoded,
+// sealed with the room key and sent over the media transport the server offered.
+// packets from other members wait in a jitter buffer per sender and are decoded,
+// mixed and played out every 20 ms
+mod audio;
+mod codec;
+mod jitter;
+mod transport;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::AbortHandle,
+};
+
+use super::protocol::VoiceOffer;
+use super::security;
+pub use audio::AudioBackend;
+use audio::{AudioSink, AudioSource};
+use codec::{CodecKind, Decoder};
+use jitter::{JitterBuffer, Playout};
+use transport::MediaPacket;
+
+pub const SAMPLE_RATE: u32 = 48_000;
+// 20 ms of mono audio
+pub const FRAME_SAMPLES: usize = SAMPLE_RATE as usize / 50;
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+// a member quiet for this long is forgotten, with their jitter buffer and decoder
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type Frame = Vec<i16>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoiceError {
+    Audio(String),
+    Codec(String),
+    Transport(String),
+}
+
+impl fmt::Display for VoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceError::Audio(e) => write!(f, "audio device: {}", e),
+            VoiceError::Codec(e) => write!(f, "codec: {}", e),
+            VoiceError::Transport(e) => write!(f, "voice connection: {}", e),
+        }
+    }
+}
+
+// a running voice session; clones share it, and it stops on `stop` or once the
+// last clone is dropped
+#[derive(Clone)]
+pub struct VoiceSession {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for VoiceSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VoiceSession")
+    }
+}
+
+struct Shared {
+    muted: AtomicBool,
+    deafened: AtomicBool,
+    stopped: AtomicBool,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+impl Shared {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// what arrived from one member
+struct Speaker {
+    jitter_buffer: JitterBuffer,
+    decoder: Option<Box<dyn Decoder>>,
+    codec: CodecKind,
+    decodes_opus: bool,
+    last_heard: Instant,
+}
+
+impl VoiceSession {
+    // connects to the media port from the offer on the chat server's host and starts
+    // capturing and playing
+    pub async fn start(
+        host: String,
+        offer: VoiceOffer,
+        room_key: [u8; 16],
+        audio: AudioBackend,
+    ) -> Result<Self, VoiceError> {
+        let (source, sink) = audio.open()?;
+        VoiceSession::start_with(host, offer, room_key, source, sink).await
+    }
+
+    async fn start_with(
+        host: String,
+        offer: VoiceOffer,
+        room_key: [u8; 16],
+        source: Box<dyn AudioSource>,
+        sink: Box<dyn AudioSink>,
+    ) -> Result<Self, VoiceError> {
+        let (mut media_sender, mut media_receiver) = transport::connect(&host, &offer).await?;
+        // everyone starts out with pcm, see `CodecKind::choose`
+        let encoder = codec::encoder(CodecKind::Pcm)?;
+        // tells our packets apart from everyone else's, including our own echoed back
+        let ssrc: u32 = rand::thread_rng().gen();
+
+        let shared = Arc::new(Shared {
+            muted: AtomicBool::new(false),
+            deafened: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            tasks: Mutex::new(vec![]),
+        });
+        let speakers: Arc<Mutex<HashMap<u32, Speaker>>> = Arc::default();
+
+        let (outgoing, mut outgoing_rx) = unbounded_channel::<Vec<u8>>();
+        let send_task = tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(transport::KEEPALIVE);
+            loop {
+                let result = tokio::select! {
+                    packet = outgoing_rx.recv() => match packet {
+                        Some(packet) => media_sender.send(&packet).await,
+                        None => return,
+                    },
+                    _ = keepalive.tick() => media_sender.keepalive().await,
+                };
+                if let Err(e) = result {
+                    println!("Voice connection lost: {}", e);
+                    return;
+                }
+            }
+        });
+
+        let receive_speakers = speakers.clone();
+        let receive_task = tokio::spawn(async move {
+            loop {
+                let bytes = match media_receiver.recv().await {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        println!("Voice connection lost: {}", e);
+                        return;
+                    }
+                };
+                let Some(packet) = MediaPacket::parse(&bytes) else {
+                    continue;
+                };
+                if packet.ssrc == ssrc {
+                    continue;
+                }
+                let Ok(payload) = security::open(&packet.payload, &room_key) else {
+                    continue;
+                };
+                let mut speakers = receive_speakers.lock().unwrap();
+                let speaker = speakers.entry(packet.ssrc).or_insert_with(|| Speaker {
+                    jitter_buffer: JitterBuffer::new(),
+                    decoder: new_decoder(packet.codec),
+                    codec: packet.codec,
+                    decodes_opus: packet.decodes_opus,
+                    last_heard: Instant::now(),
+                });
+                speaker.decodes_opus = packet.decodes_opus;
+                speaker.last_heard = Instant::now();
+                if payload.is_empty() {
+                    continue;
+                }
+                // members switch codec as others come and go
+                if speaker.codec != packet.codec {
+                    speaker.jitter_buffer = JitterBuffer::new();
+                    speaker.decoder = new_decoder(packet.codec);
+                    speaker.codec = packet.codec;
+                }
+                speaker.jitter_buffer.push(packet.sequence, payload);
+            }
+        });
+        shared
+            .tasks
+            .lock()
+            .unwrap()
+            .extend([send_task.abort_handle(), receive_task.abort_handle()]);
+
+        let capture_shared = Arc::downgrade(&shared);
+        let capture_speakers = speakers.clone();
+        thread::spawn(move || {
+            capture(
+                source,
+                encoder,
+                ssrc,
+                room_key,
+                outgoing,
+                capture_speakers,
+                capture_shared,
+            )
+        });
+        let playout_shared = Arc::downgrade(&shared);
+        thread::spawn(move || play_out(sink, speakers, playout_shared));
+
+        Ok(VoiceSession { shared })
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.shared.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn set_deafened(&self, deafened: bool) {
+        self.shared.deafened.store(deafened, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.shared.stop();
+    }
+}
+
+// a member sending a codec we cannot decode is not played, which `CodecKind::choose`
+// keeps from happening between members of this client
+fn new_decoder(codec: CodecKind) -> Option<Box<dyn Decoder>> {
+    match codec::decoder(codec) {
+        Ok(decoder) => Some(decoder),
+        Err(e) => {
+            println!("Could not play a member's voice: {}", e);
+            None
+        }
+    }
+}
+
+// the audio threads only hold on to the session weakly, so they end with it
+fn capture(
+    mut source: Box<dyn AudioSource>,
+    mut encoder: Box<dyn codec::Encoder>,
+    ssrc: u32,
+    room_key: [u8; 16],
+    outgoing: UnboundedSender<Vec<u8>>,
+    speakers: Arc<Mutex<HashMap<u32, Speaker>>>,
+    shared: Weak<Shared>,
+) {
+    let mut codec = CodecKind::Pcm;
+    let mut sequence: u16 = 0;
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        let frame = source.next_frame();
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let wanted = CodecKind::choose(
+            speakers
+                .lock()
+                .unwrap()
+                .values()
+                .map(|speaker| speaker.decodes_opus),
+        );
+        if wanted != codec {
+            match codec::encoder(wanted) {
+                Ok(switched) => {
+                    encoder = switched;
+                    codec = wanted;
+                }
+                Err(e) => println!("Could not switch voice codec: {}", e),
+            }
+        }
+        let frame = frame.filter(|_| !shared.muted.load(Ordering::Relaxed));
+        let payload = match frame {
+            Some(frame) => match encoder.encode(&frame) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    println!("Could not encode voice: {}", e);
+                    continue;
+                }
+            },
+            // while quiet, members still need to hear what we can decode
+            None if last_sent.is_none_or(|at| at.elapsed() >= transport::KEEPALIVE) => {
+                vec![]
+            }
+            None => continue,
+        };
+        let packet = MediaPacket {
+            codec,
+            decodes_opus: codec::opus_available(),
+            ssrc,
+            sequence,
+            payload: security::seal(&payload, &room_key),
+        };
+        // only audio is played in sequence
+        if !payload.is_empty() {
+            sequence = sequence.wrapping_add(1);
+        }
+        last_sent = Some(Instant::now());
+        if outgoing.send(packet.serialize()).is_err() {
+            return;
+        }
+    }
+}
+
+fn play_out(
+    mut sink: Box<dyn AudioSink>,
+    speakers: Arc<Mutex<HashMap<u32, Speaker>>>,
+    shared: Weak<Shared>,
+) {
+    let mut deadline = Instant::now();
+    loop {
+        deadline += FRAME_DURATION;
+        let now = Instant::now();
+        match deadline.checked_duration_since(now) {
+            Some(wait) => thread::sleep(wait),
+            // far behind, e.g. after a suspend: start over instead of rushing
+            None if now - deadline > FRAME_DURATION * 5 => deadline = now,
+            None => {}
+        }
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut mix: Option<Vec<i32>> = None;
+        {
+            let mut speakers = speakers.lock().unwrap();
+            speakers.retain(|_, speaker| speaker.last_heard.elapsed() < SPEAKER_TIMEOUT);
+            for speaker in speakers.values_mut() {
+                let packet = match speaker.jitter_buffer.pop() {
+                    Playout::Waiting => continue,
+                    Playout::Lost => None,
+                    Playout::Packet(packet) => Some(packet),
+                };
+                let Some(decoder) = &mut speaker.decoder else {
+                    continue;
+                };
+                match decoder.decode(packet.as_deref()) {
+                    Ok(frame) => {
+                        let mix = mix.get_or_insert_with(|| vec![0; FRAME_SAMPLES]);
+                        for (mixed, sample) in mix.iter_mut().zip(frame) {
+                            *mixed += i32::from(sample);
+                        }
+                    }
+                    Err(e) => println!("Could not decode voice: {}", e),
+                }
+            }
+        }
+
+        if let Some(mix) = mix {
+            if !shared.deafened.load(Ordering::Relaxed) {
+                let frame: Frame = mix
+                    .into_iter()
+                    .map(|sample| sample.clamp(i16::MIN.into(), i16::MAX.into()) as i16)
+                    .collect();
+                sink.play(&frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{tcp::OwnedWriteHalf, TcpListener, UdpSocket},
+        time::timeout,
+    };
+
+    use super::audio::LoopbackAudio;
+    use super::*;
+    use crate::app::codec::{self as wire, FrameDecoder};
+    use crate::app::protocol::MediaTransport;
+
+    // stand-in for the server's media port: learns each client's address from its
+    // token and relays every packet to all other clients
+    async fn start_udp_relay() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut clients: HashMap<Vec<u8>, SocketAddr> = HashMap::new();
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let datagram = &buf[..len];
+                let token_len = datagram[0] as usize;
+                let token = datagram[1..1 + token_len].to_vec();
+                clients.insert(token.clone(), from);
+                let packet = &datagram[1 + token_len..];
+                if packet.is_empty() {
+                    continue;
+                }
+                for (other, addr) in &clients {
+                    if *other != token {
+                        socket.send_to(packet, addr).await.unwrap();
+                    }
+                }
+            }
+        });
+        port
+    }
+
+    async fn start_tcp_relay() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let clients: Arc<tokio::sync::Mutex<Vec<OwnedWriteHalf>>> = Arc::default();
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let (mut reader, writer) = tcp_stream.into_split();
+                let index = {
+                    let mut clients = clients.lock().await;
+                    clients.push(writer);
+                    clients.len() - 1
+                };
+                let clients = clients.clone();
+                tokio::spawn(async move {
+                    let mut decoder = FrameDecoder::new();
+                    let mut buf = [0u8; 4096];
+                    let mut greeted = false;
+                    loop {
+                        let Ok(bytes_read) = reader.read(&mut buf).await else {
+                            return;
+                        };
+                        if bytes_read == 0 {
+                            return;
+                        }
+                        decoder.push(&buf[..bytes_read]);
+                        while let Some(frame) = decoder.next_frame().unwrap() {
+                            // the first frame is the token
+                            if !greeted {
+                                greeted = true;
+                                continue;
+                            }
+                            let mut clients = clients.lock().await;
+                            for (other, writer) in clients.iter_mut().enumerate() {
+                                if other != index {
+                                    let _ = writer.write_all(&wire::encode(&frame)).await;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn tone(frame_index: usize) -> Frame {
+        (0..FRAME_SAMPLES)
+            .map(|i| (((frame_index * FRAME_SAMPLES + i) % 100) as i16 - 50) * 300)
+            .collect()
+    }
+
+    // alice speaks a few frames, bob has to play exactly those; opus is lossy, so
+    // once both can decode it bob only has to play something
+    async fn carries_voice(transport: MediaTransport, port: u16) {
+        let room_key = *security::derive_room_key("passphrase", "1").bytes();
+        let offer = |token: &str| VoiceOffer {
+            transport,
+            port,
+            token: token.to_string(),
+        };
+        let (alice_audio, alice_source, alice_sink) = LoopbackAudio::new();
+        let (bob_audio, bob_source, bob_sink) = LoopbackAudio::new();
+        let host = "127.0.0.1".to_string();
+        let _alice = VoiceSession::start_with(
+            host.clone(),
+            offer("alice"),
+            room_key,
+            alice_source,
+            alice_sink,
+        )
+        .await
+        .unwrap();
+        let _bob = VoiceSession::start_with(host, offer("bob"), room_key, bob_source, bob_sink)
+            .await
+            .unwrap();
+        // give both a moment to register with the relay
+        tokio::time::sleep(transport::KEEPALIVE + Duration::from_millis(200)).await;
+
+        let spoken: Vec<Frame> = (0..10).map(tone).collect();
+        for frame in &spoken {
+            alice_audio.speak(frame.clone());
+        }
+        let expected: Vec<i16> = spoken.concat();
+        let heard = |played: Vec<i16>| {
+            if codec::opus_available() {
+                played.iter().any(|sample| *sample != 0)
+            } else {
+                played.windows(expected.len()).any(|w| w == expected)
+            }
+        };
+        timeout(Duration::from_secs(10), async {
+            while !heard(bob_audio.played()) {
+                tokio::time::sleep(FRAME_DURATION).await;
+            }
+        })
+        .await
+        .expect("bob never played what alice said");
+        // nobody hears themselves
+        assert!(alice_audio.played().iter().all(|sample| *sample == 0));
+    }
+
+    #[tokio::test]
+    async fn carries_voice_over_udp() {
+        let port = start_udp_relay().await;
+        carries_voice(MediaTransport::Udp, port).await;
+    }
+
+    #[tokio::test]
+    async fn carries_voice_over_tcp() {
+        let port = start_tcp_relay().await;
+        carries_voice(MediaTransport::Tcp, port).await;
+    }
+}
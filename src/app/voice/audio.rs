@@ -0,0 +1,388 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    thread,
+    time::Instant,
+};
+
+use super::{Frame, VoiceError, FRAME_DURATION, FRAME_SAMPLES};
+
+// hands over captured audio one 20 ms frame at a time; None when nothing was
+// captured for a frame's time, so the caller gets to check whether to stop
+pub trait AudioSource: Send {
+    fn next_frame(&mut self) -> Option<Frame>;
+}
+
+pub trait AudioSink: Send {
+    fn play(&mut self, frame: &[i16]);
+}
+
+pub type OpenAudio = (Box<dyn AudioSource>, Box<dyn AudioSink>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioBackend {
+    // the default sound card, only with the native-audio feature
+    Device,
+    // raw 48 kHz mono s16le: the input file is spoken in real time, then silence,
+    // and everything heard is appended to the output file
+    Files { input: PathBuf, output: PathBuf },
+}
+
+impl AudioBackend {
+    pub fn open(&self) -> Result<OpenAudio, VoiceError> {
+        match self {
+            AudioBackend::Device => device::open(),
+            AudioBackend::Files { input, output } => {
+                let open_error = |path: &PathBuf, e: std::io::Error| {
+                    VoiceError::Audio(format!("{}: {}", path.display(), e))
+                };
+                let input_file = File::open(input).map_err(|e| open_error(input, e))?;
+                let output_file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(output)
+                    .map_err(|e| open_error(output, e))?;
+                Ok((
+                    Box::new(FileSource {
+                        reader: BufReader::new(input_file),
+                        next_due: Instant::now(),
+                    }),
+                    Box::new(FileSink {
+                        writer: BufWriter::new(output_file),
+                    }),
+                ))
+            }
+        }
+    }
+}
+
+struct FileSource {
+    reader: BufReader<File>,
+    next_due: Instant,
+}
+
+impl AudioSource for FileSource {
+    fn next_frame(&mut self) -> Option<Frame> {
+        // paced like a microphone would be
+        self.next_due = self.next_due.max(Instant::now() - FRAME_DURATION) + FRAME_DURATION;
+        thread::sleep(self.next_due.saturating_duration_since(Instant::now()));
+
+        let mut bytes = vec![0u8; FRAME_SAMPLES * 2];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match self.reader.read(&mut bytes[filled..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => filled += n,
+            }
+        }
+        if filled == 0 {
+            return None;
+        }
+        // a short last frame is padded with silence
+        Some(
+            bytes
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                .collect(),
+        )
+    }
+}
+
+struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl AudioSink for FileSink {
+    fn play(&mut self, frame: &[i16]) {
+        let bytes: Vec<u8> = frame
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let result = self
+            .writer
+            .write_all(&bytes)
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = result {
+            println!("Could not write audio: {}", e);
+        }
+    }
+}
+
+#[cfg(not(feature = "native-audio"))]
+mod device {
+    use super::{OpenAudio, VoiceError};
+
+    pub fn open() -> Result<OpenAudio, VoiceError> {
+        Err(VoiceError::Audio(String::from(
+            "built without the native-audio feature, set voice_input and voice_output to use files",
+        )))
+    }
+}
+
+#[cfg(feature = "native-audio")]
+mod device {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            mpsc::{self, Receiver, RecvTimeoutError, Sender},
+            Arc, Mutex,
+        },
+        thread,
+    };
+
+    use cpal::{
+        traits::{DeviceTrait, HostTrait, StreamTrait},
+        FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+        SupportedStreamConfig, SupportedStreamConfigRange,
+    };
+
+    use super::super::{Frame, FRAME_DURATION, FRAME_SAMPLES, SAMPLE_RATE};
+    use super::{AudioSink, AudioSource, OpenAudio, VoiceError};
+
+    // more than this much queued playback means we fell behind, the oldest goes
+    const MAX_PLAYBACK_SAMPLES: usize = FRAME_SAMPLES * 10;
+
+    type Playback = Arc<Mutex<VecDeque<i16>>>;
+
+    // cpal streams cannot leave the thread that made them, so one thread owns both
+    // and keeps them running until the source is dropped
+    pub fn open() -> Result<OpenAudio, VoiceError> {
+        let (captured, captured_rx) = mpsc::channel();
+        let playback: Playback = Arc::default();
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let (ready, ready_rx) = mpsc::channel();
+        let device_playback = playback.clone();
+        thread::spawn(move || {
+            let streams = match start_streams(captured, device_playback) {
+                Ok(streams) => {
+                    let _ = ready.send(Ok(()));
+                    streams
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            // returns once the source, holding the sender, is gone
+            let _ = stop_rx.recv();
+            drop(streams);
+        });
+        ready_rx
+            .recv()
+            .map_err(|_| VoiceError::Audio(String::from("audio thread failed to start")))??;
+        Ok((
+            Box::new(DeviceSource {
+                captured: captured_rx,
+                pending: vec![],
+                _stop: stop,
+            }),
+            Box::new(DeviceSink { playback }),
+        ))
+    }
+
+    struct DeviceSource {
+        captured: Receiver<Vec<i16>>,
+        // samples captured beyond the last whole frame
+        pending: Vec<i16>,
+        _stop: Sender<()>,
+    }
+
+    impl AudioSource for DeviceSource {
+        fn next_frame(&mut self) -> Option<Frame> {
+            while self.pending.len() < FRAME_SAMPLES {
+                match self.captured.recv_timeout(FRAME_DURATION) {
+                    Ok(samples) => self.pending.extend(samples),
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                        return None
+                    }
+                }
+            }
+            let rest = self.pending.split_off(FRAME_SAMPLES);
+            Some(std::mem::replace(&mut self.pending, rest))
+        }
+    }
+
+    struct DeviceSink {
+        playback: Playback,
+    }
+
+    impl AudioSink for DeviceSink {
+        fn play(&mut self, frame: &[i16]) {
+            let mut playback = self.playback.lock().unwrap();
+            playback.extend(frame);
+            let excess = playback.len().saturating_sub(MAX_PLAYBACK_SAMPLES);
+            playback.drain(..excess);
+        }
+    }
+
+    fn start_streams(
+        captured: Sender<Vec<i16>>,
+        playback: Playback,
+    ) -> Result<(Stream, Stream), VoiceError> {
+        let host = cpal::default_host();
+        let input = host
+            .default_input_device()
+            .ok_or_else(|| VoiceError::Audio(String::from("no microphone found")))?;
+        let output = host
+            .default_output_device()
+            .ok_or_else(|| VoiceError::Audio(String::from("no speakers found")))?;
+
+        let input_config = at_sample_rate(input.supported_input_configs().map_err(audio_error)?)?;
+        let input_stream = match input_config.sample_format() {
+            SampleFormat::I16 => build_input::<i16>(&input, &input_config.config(), captured),
+            SampleFormat::U16 => build_input::<u16>(&input, &input_config.config(), captured),
+            SampleFormat::F32 => build_input::<f32>(&input, &input_config.config(), captured),
+            format => Err(VoiceError::Audio(format!(
+                "unsupported sample format {}",
+                format
+            ))),
+        }?;
+
+        let output_config =
+            at_sample_rate(output.supported_output_configs().map_err(audio_error)?)?;
+        let output_stream = match output_config.sample_format() {
+            SampleFormat::I16 => build_output::<i16>(&output, &output_config.config(), playback),
+            SampleFormat::U16 => build_output::<u16>(&output, &output_config.config(), playback),
+            SampleFormat::F32 => build_output::<f32>(&output, &output_config.config(), playback),
+            format => Err(VoiceError::Audio(format!(
+                "unsupported sample format {}",
+                format
+            ))),
+        }?;
+
+        input_stream.play().map_err(audio_error)?;
+        output_stream.play().map_err(audio_error)?;
+        Ok((input_stream, output_stream))
+    }
+
+    // we do not resample, so the device has to run at the codec's rate; the fewest
+    // channels wins since we only use the first one
+    fn at_sample_rate(
+        configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    ) -> Result<SupportedStreamConfig, VoiceError> {
+        let rate = SampleRate(SAMPLE_RATE);
+        configs
+            .filter(|config| config.min_sample_rate() <= rate && rate <= config.max_sample_rate())
+            .min_by_key(|config| config.channels())
+            .map(|config| config.with_sample_rate(rate))
+            .ok_or_else(|| VoiceError::Audio(format!("device does not support {} Hz", SAMPLE_RATE)))
+    }
+
+    fn build_input<T>(
+        device: &cpal::Device,
+        config: &StreamConfig,
+        captured: Sender<Vec<i16>>,
+    ) -> Result<Stream, VoiceError>
+    where
+        T: SizedSample,
+        i16: FromSample<T>,
+    {
+        let channels = usize::from(config.channels);
+        device
+            .build_input_stream(
+                config,
+                move |data: &[T], _: &cpal::InputCallbackInfo| {
+                    let mono = data
+                        .chunks(channels)
+                        .map(|samples| i16::from_sample(samples[0]))
+                        .collect();
+                    let _ = captured.send(mono);
+                },
+                |e| println!("Microphone error: {}", e),
+                None,
+            )
+            .map_err(audio_error)
+    }
+
+    fn build_output<T>(
+        device: &cpal::Device,
+        config: &StreamConfig,
+        playback: Playback,
+    ) -> Result<Stream, VoiceError>
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        let channels = usize::from(config.channels);
+        device
+            .build_output_stream(
+                config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    let mut playback = playback.lock().unwrap();
+                    for samples in data.chunks_mut(channels) {
+                        // an empty queue plays silence
+                        let sample = playback.pop_front().unwrap_or(0);
+                        samples.fill(T::from_sample(sample));
+                    }
+                },
+                |e| println!("Speaker error: {}", e),
+                None,
+            )
+            .map_err(audio_error)
+    }
+
+    fn audio_error(e: impl std::fmt::Display) -> VoiceError {
+        VoiceError::Audio(e.to_string())
+    }
+}
+
+// in-memory audio for tests: frames handed to `speak` are captured, whatever is
+// played collects in `played`
+#[cfg(test)]
+#[derive(Clone)]
+pub struct LoopbackAudio {
+    input: std::sync::mpsc::Sender<Frame>,
+    played: std::sync::Arc<std::sync::Mutex<Vec<i16>>>,
+}
+
+#[cfg(test)]
+impl LoopbackAudio {
+    pub fn new() -> (Self, Box<dyn AudioSource>, Box<dyn AudioSink>) {
+        let (input, input_rx) = std::sync::mpsc::channel();
+        let played = std::sync::Arc::<std::sync::Mutex<Vec<i16>>>::default();
+        let loopback = LoopbackAudio {
+            input,
+            played: played.clone(),
+        };
+        (
+            loopback,
+            Box::new(LoopbackSource { input: input_rx }),
+            Box::new(LoopbackSink { played }),
+        )
+    }
+
+    pub fn speak(&self, frame: Frame) {
+        self.input.send(frame).unwrap();
+    }
+
+    pub fn played(&self) -> Vec<i16> {
+        self.played.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+struct LoopbackSource {
+    input: std::sync::mpsc::Receiver<Frame>,
+}
+
+#[cfg(test)]
+impl AudioSource for LoopbackSource {
+    fn next_frame(&mut self) -> Option<Frame> {
+        let frame = self.input.recv_timeout(FRAME_DURATION).ok()?;
+        // as fast as a microphone, so the jitter buffer sees a steady stream
+        thread::sleep(FRAME_DURATION);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+struct LoopbackSink {
+    played: std::sync::Arc<std::sync::Mutex<Vec<i16>>>,
+}
+
+#[cfg(test)]
+impl AudioSink for LoopbackSink {
+    fn play(&mut self, frame: &[i16]) {
+        self.played.lock().unwrap().extend_from_slice(frame);
+    }
+}
@@ -0,0 +1,209 @@
+use super::{Frame, VoiceError, FRAME_SAMPLES};
+
+// which codec a packet's payload is in. every packet also says whether its sender
+// can decode opus, and opus is only sent once everyone we hear can, so members
+// built without it still hear the rest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecKind {
+    // raw s16le, four times the bandwidth of opus but needs no library
+    Pcm,
+    Opus,
+}
+
+impl CodecKind {
+    // what to send, given for each member we hear whether they can decode opus;
+    // pcm until we hear someone, since a member who has not spoken yet may lack it
+    pub fn choose(peers_decode_opus: impl IntoIterator<Item = bool>) -> Self {
+        let mut peers_decode_opus = peers_decode_opus.into_iter().peekable();
+        if opus_available()
+            && peers_decode_opus.peek().is_some()
+            && peers_decode_opus.all(|decodes| decodes)
+        {
+            CodecKind::Opus
+        } else {
+            CodecKind::Pcm
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            CodecKind::Pcm => 0,
+            CodecKind::Opus => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(CodecKind::Pcm),
+            1 => Some(CodecKind::Opus),
+            _ => None,
+        }
+    }
+}
+
+pub fn opus_available() -> bool {
+    cfg!(feature = "native-audio")
+}
+
+pub trait Encoder: Send {
+    fn encode(&mut self, frame: &[i16]) -> Result<Vec<u8>, VoiceError>;
+}
+
+pub trait Decoder: Send {
+    // None asks for a stand-in for a lost packet
+    fn decode(&mut self, packet: Option<&[u8]>) -> Result<Frame, VoiceError>;
+}
+
+pub fn encoder(kind: CodecKind) -> Result<Box<dyn Encoder>, VoiceError> {
+    match kind {
+        CodecKind::Pcm => Ok(Box::new(Pcm)),
+        CodecKind::Opus => opus::encoder(),
+    }
+}
+
+pub fn decoder(kind: CodecKind) -> Result<Box<dyn Decoder>, VoiceError> {
+    match kind {
+        CodecKind::Pcm => Ok(Box::new(Pcm)),
+        CodecKind::Opus => opus::decoder(),
+    }
+}
+
+struct Pcm;
+
+impl Encoder for Pcm {
+    fn encode(&mut self, frame: &[i16]) -> Result<Vec<u8>, VoiceError> {
+        Ok(frame
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect())
+    }
+}
+
+impl Decoder for Pcm {
+    fn decode(&mut self, packet: Option<&[u8]>) -> Result<Frame, VoiceError> {
+        let Some(packet) = packet else {
+            return Ok(vec![0; FRAME_SAMPLES]);
+        };
+        if packet.len() != FRAME_SAMPLES * 2 {
+            return Err(VoiceError::Codec(format!(
+                "pcm frame of {} bytes, expected {}",
+                packet.len(),
+                FRAME_SAMPLES * 2
+            )));
+        }
+        Ok(packet
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect())
+    }
+}
+
+#[cfg(not(feature = "native-audio"))]
+mod opus {
+    use super::{Decoder, Encoder, VoiceError};
+
+    fn unavailable() -> VoiceError {
+        VoiceError::Codec(String::from(
+            "built without the native-audio feature, opus is not available",
+        ))
+    }
+
+    pub fn encoder() -> Result<Box<dyn Encoder>, VoiceError> {
+        Err(unavailable())
+    }
+
+    pub fn decoder() -> Result<Box<dyn Decoder>, VoiceError> {
+        Err(unavailable())
+    }
+}
+
+#[cfg(feature = "native-audio")]
+mod opus {
+    use audiopus::{
+        coder::{Decoder as OpusDecoder, Encoder as OpusEncoder},
+        packet::Packet,
+        Application, Channels, MutSignals, SampleRate,
+    };
+
+    use super::super::{Frame, FRAME_SAMPLES};
+    use super::{Decoder, Encoder, VoiceError};
+
+    // far more than a 20 ms voice frame ever needs
+    const MAX_PACKET_LEN: usize = 4000;
+
+    fn codec_error(e: audiopus::Error) -> VoiceError {
+        VoiceError::Codec(e.to_string())
+    }
+
+    struct Opus(OpusEncoder);
+
+    pub fn encoder() -> Result<Box<dyn Encoder>, VoiceError> {
+        let encoder = OpusEncoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)
+            .map_err(codec_error)?;
+        Ok(Box::new(Opus(encoder)))
+    }
+
+    impl Encoder for Opus {
+        fn encode(&mut self, frame: &[i16]) -> Result<Vec<u8>, VoiceError> {
+            let mut packet = vec![0u8; MAX_PACKET_LEN];
+            let len = self.0.encode(frame, &mut packet).map_err(codec_error)?;
+            packet.truncate(len);
+            Ok(packet)
+        }
+    }
+
+    struct OpusPlayer(OpusDecoder);
+
+    pub fn decoder() -> Result<Box<dyn Decoder>, VoiceError> {
+        let decoder = OpusDecoder::new(SampleRate::Hz48000, Channels::Mono).map_err(codec_error)?;
+        Ok(Box::new(OpusPlayer(decoder)))
+    }
+
+    impl Decoder for OpusPlayer {
+        fn decode(&mut self, packet: Option<&[u8]>) -> Result<Frame, VoiceError> {
+            // without a packet opus conceals the gap from what it heard before
+            let packet = packet
+                .map(Packet::try_from)
+                .transpose()
+                .map_err(codec_error)?;
+            let mut frame = vec![0i16; FRAME_SAMPLES];
+            let signals = MutSignals::try_from(&mut frame).map_err(codec_error)?;
+            let len = self.0.decode(packet, signals, false).map_err(codec_error)?;
+            frame.truncate(len);
+            frame.resize(FRAME_SAMPLES, 0);
+            Ok(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcm_round_trips_and_conceals_loss() {
+        let frame: Frame = (0..FRAME_SAMPLES as i16).map(|i| i * 7 - 3000).collect();
+        let packet = encoder(CodecKind::Pcm).unwrap().encode(&frame).unwrap();
+        let mut decoder = decoder(CodecKind::Pcm).unwrap();
+        assert_eq!(decoder.decode(Some(&packet)).unwrap(), frame);
+        assert_eq!(decoder.decode(None).unwrap(), vec![0; FRAME_SAMPLES]);
+        assert!(decoder.decode(Some(&packet[1..])).is_err());
+
+        for kind in [CodecKind::Pcm, CodecKind::Opus] {
+            assert_eq!(CodecKind::from_byte(kind.to_byte()), Some(kind));
+        }
+        assert_eq!(CodecKind::from_byte(9), None);
+    }
+
+    #[test]
+    fn sends_opus_only_when_everyone_decodes_it() {
+        assert_eq!(CodecKind::choose([]), CodecKind::Pcm);
+        assert_eq!(CodecKind::choose([true, false]), CodecKind::Pcm);
+        let opus = if opus_available() {
+            CodecKind::Opus
+        } else {
+            CodecKind::Pcm
+        };
+        assert_eq!(CodecKind::choose([true, true]), opus);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+
+// packets to hold before starting to play, about 60 ms of cover for late arrivals
+const TARGET_DEPTH: usize = 3;
+// a sequence number this far from what we expect means the sender started over
+const MAX_JUMP: u16 = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    // nothing to play yet, or we ran dry and are buffering again
+    Waiting,
+    // the packet due now never came but later ones did
+    Lost,
+    Packet(Vec<u8>),
+}
+
+// puts one member's packets back in order and evens out how they arrive
+#[derive(Debug, Default)]
+pub struct JitterBuffer {
+    // sequence number of the front slot
+    next_sequence: Option<u16>,
+    slots: VecDeque<Option<Vec<u8>>>,
+    buffering: bool,
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        JitterBuffer {
+            buffering: true,
+            ..JitterBuffer::default()
+        }
+    }
+
+    pub fn push(&mut self, sequence: u16, payload: Vec<u8>) {
+        let next_sequence = *self.next_sequence.get_or_insert(sequence);
+        let mut offset = sequence.wrapping_sub(next_sequence);
+        // too late to play, its slot is gone
+        if offset > u16::MAX / 2 && next_sequence.wrapping_sub(sequence) < MAX_JUMP {
+            return;
+        }
+        if offset >= MAX_JUMP && offset as usize >= self.slots.len() + MAX_JUMP as usize {
+            self.slots.clear();
+            self.next_sequence = Some(sequence);
+            self.buffering = true;
+            offset = 0;
+        }
+        let offset = offset as usize;
+        if self.slots.len() <= offset {
+            self.slots.resize(offset + 1, None);
+        }
+        self.slots[offset] = Some(payload);
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if self.buffering {
+            if self.slots.len() < TARGET_DEPTH {
+                return Playout::Waiting;
+            }
+            self.buffering = false;
+        }
+        let Some(slot) = self.slots.pop_front() else {
+            // ran dry, wait for enough to cover the next hiccup
+            self.buffering = true;
+            return Playout::Waiting;
+        };
+        self.next_sequence = self.next_sequence.map(|sequence| sequence.wrapping_add(1));
+        match slot {
+            Some(payload) => Playout::Packet(payload),
+            None => Playout::Lost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(buffer: &mut JitterBuffer) -> Vec<Playout> {
+        let mut played = vec![];
+        loop {
+            match buffer.pop() {
+                Playout::Waiting => return played,
+                playout => played.push(playout),
+            }
+        }
+    }
+
+    fn packet(byte: u8) -> Playout {
+        Playout::Packet(vec![byte])
+    }
+
+    #[test]
+    fn waits_for_depth_then_reorders() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(10, vec![0]);
+        buffer.push(11, vec![1]);
+        assert_eq!(buffer.pop(), Playout::Waiting);
+        buffer.push(13, vec![3]);
+        buffer.push(12, vec![2]);
+        assert_eq!(
+            drain(&mut buffer),
+            vec![packet(0), packet(1), packet(2), packet(3)]
+        );
+
+        // dry now, so it buffers again before playing
+        buffer.push(14, vec![4]);
+        assert_eq!(buffer.pop(), Playout::Waiting);
+    }
+
+    #[test]
+    fn reports_losses_and_drops_late_packets() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(0, vec![0]);
+        buffer.push(2, vec![2]);
+        buffer.push(3, vec![3]);
+        assert_eq!(buffer.pop(), packet(0));
+        assert_eq!(buffer.pop(), Playout::Lost);
+        // its turn has passed
+        buffer.push(1, vec![1]);
+        buffer.push(0, vec![0]);
+        assert_eq!(drain(&mut buffer), vec![packet(2), packet(3)]);
+    }
+
+    #[test]
+    fn follows_wraparound_and_restarts() {
+        let mut buffer = JitterBuffer::new();
+        for sequence in [u16::MAX - 1, u16::MAX, 0, 1] {
+            buffer.push(sequence, vec![sequence as u8]);
+        }
+        assert_eq!(
+            drain(&mut buffer),
+            vec![packet(254), packet(255), packet(0), packet(1)]
+        );
+
+        // the sender started over somewhere else entirely
+        buffer.push(30_000, vec![7]);
+        buffer.push(30_001, vec![8]);
+        buffer.push(30_002, vec![9]);
+        assert_eq!(drain(&mut buffer), vec![packet(7), packet(8), packet(9)]);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        lookup_host,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, UdpSocket,
+    },
+};
+
+use super::codec::CodecKind;
+use super::VoiceError;
+use crate::app::codec::{self as wire, FrameDecoder};
+use crate::app::protocol::{MediaTransport, VoiceOffer};
+
+// how often we remind the server, and any NAT on the way, that we are listening
+pub const KEEPALIVE: Duration = Duration::from_secs(1);
+
+const PACKET_VERSION: u8 = 1;
+// version, codec, whether the sender can decode opus, ssrc and sequence number
+const HEADER_LEN: usize = 9;
+
+// a voice packet as relayed by the server; the payload is sealed with the room key
+// so the server only sees the header. a sealed empty payload carries no audio, only
+// the header, and is sent now and then while we have nothing to say
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaPacket {
+    pub codec: CodecKind,
+    pub decodes_opus: bool,
+    pub ssrc: u32,
+    pub sequence: u16,
+    pub payload: Vec<u8>,
+}
+
+impl MediaPacket {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push(PACKET_VERSION);
+        bytes.push(self.codec.to_byte());
+        bytes.push(u8::from(self.decodes_opus));
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    // None for anything we do not understand, which is simply not played
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] != PACKET_VERSION {
+            return None;
+        }
+        Some(MediaPacket {
+            codec: CodecKind::from_byte(bytes[1])?,
+            decodes_opus: bytes[2] == 1,
+            ssrc: u32::from_be_bytes(bytes[3..7].try_into().ok()?),
+            sequence: u16::from_be_bytes(bytes[7..9].try_into().ok()?),
+            payload: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+fn transport_error(e: impl std::fmt::Display) -> VoiceError {
+    VoiceError::Transport(e.to_string())
+}
+
+pub enum MediaSender {
+    // every datagram starts with the token, one without a packet is a keepalive
+    Udp {
+        socket: Arc<UdpSocket>,
+        token: Vec<u8>,
+    },
+    Tcp(OwnedWriteHalf),
+}
+
+pub enum MediaReceiver {
+    Udp(Arc<UdpSocket>),
+    Tcp {
+        reader: OwnedReadHalf,
+        decoder: FrameDecoder,
+    },
+}
+
+// opens the transport the server offered: plain datagrams for udp, or the same
+// length-prefixed frames as the chat connection for tcp, the first one being the token
+pub async fn connect(
+    host: &str,
+    offer: &VoiceOffer,
+) -> Result<(MediaSender, MediaReceiver), VoiceError> {
+    match offer.transport {
+        MediaTransport::Udp => {
+            let token = offer.token.as_bytes().to_vec();
+            if token.len() > usize::from(u8::MAX) {
+                return Err(VoiceError::Transport(String::from("voice token too long")));
+            }
+            let address = lookup_host((host, offer.port))
+                .await
+                .map_err(transport_error)?
+                .next()
+                .ok_or_else(|| VoiceError::Transport(format!("could not resolve {}", host)))?;
+            let local = if address.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(local).await.map_err(transport_error)?;
+            socket.connect(address).await.map_err(transport_error)?;
+            let socket = Arc::new(socket);
+            let mut sender = MediaSender::Udp {
+                socket: socket.clone(),
+                token,
+            };
+            // so the server knows where to send to before we say anything
+            sender.keepalive().await?;
+            Ok((sender, MediaReceiver::Udp(socket)))
+        }
+        MediaTransport::Tcp => {
+            let tcp_stream = TcpStream::connect((host, offer.port))
+                .await
+                .map_err(transport_error)?;
+            tcp_stream.set_nodelay(true).map_err(transport_error)?;
+            let (reader, mut writer) = tcp_stream.into_split();
+            writer
+                .write_all(&wire::encode(offer.token.as_bytes()))
+                .await
+                .map_err(transport_error)?;
+            Ok((
+                MediaSender::Tcp(writer),
+                MediaReceiver::Tcp {
+                    reader,
+                    decoder: FrameDecoder::new(),
+                },
+            ))
+        }
+    }
+}
+
+impl MediaSender {
+    pub async fn send(&mut self, packet: &[u8]) -> Result<(), VoiceError> {
+        match self {
+            MediaSender::Udp { socket, token } => {
+                let mut datagram = Vec::with_capacity(1 + token.len() + packet.len());
+                datagram.push(token.len() as u8);
+                datagram.extend_from_slice(token);
+                datagram.extend_from_slice(packet);
+                socket.send(&datagram).await.map_err(transport_error)?;
+            }
+            MediaSender::Tcp(writer) => {
+                writer
+                    .write_all(&wire::encode(packet))
+                    .await
+                    .map_err(transport_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn keepalive(&mut self) -> Result<(), VoiceError> {
+        match self {
+            MediaSender::Udp { .. } => self.send(&[]).await,
+            // the connection itself tells the server we are there
+            MediaSender::Tcp(_) => Ok(()),
+        }
+    }
+}
+
+impl MediaReceiver {
+    pub async fn recv(&mut self) -> Result<Vec<u8>, VoiceError> {
+        match self {
+            MediaReceiver::Udp(socket) => {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    match socket.recv(&mut buf).await {
+                        Ok(len) => return Ok(buf[..len].to_vec()),
+                        // an icmp error for an earlier datagram, e.g. before the
+                        // server was listening; later ones may still get through
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                        Err(e) => return Err(transport_error(e)),
+                    }
+                }
+            }
+            MediaReceiver::Tcp { reader, decoder } => {
+                let mut buf = [0u8; 4096];
+                loop {
+                    if let Some(frame) = decoder.next_frame().map_err(transport_error)? {
+                        return Ok(frame);
+                    }
+                    let bytes_read = reader.read(&mut buf).await.map_err(transport_error)?;
+                    if bytes_read == 0 {
+                        return Err(VoiceError::Transport(String::from(
+                            "server closed the connection",
+                        )));
+                    }
+                    decoder.push(&buf[..bytes_read]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let packet = MediaPacket {
+            codec: CodecKind::Pcm,
+            decodes_opus: true,
+            ssrc: 0xdead_beef,
+            sequence: 65535,
+            payload: vec![1, 2, 3],
+        };
+        assert_eq!(MediaPacket::parse(&packet.serialize()), Some(packet));
+        assert_eq!(MediaPacket::parse(&[PACKET_VERSION, 0, 0]), None);
+        assert_eq!(MediaPacket::parse(&[9, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+    }
+}
@@ -1,46 +1,147 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-
 use iced::advanced::graphics::core::font;
-use iced::widget::{button, column, container, text, text_input};
-use iced::{alignment, Element, Font};
+use iced::task::Handle;
+use iced::widget::{button, column, container, row, text, text_input, Row};
+use iced::{alignment, Element, Font, Task};
+
+use super::chat::RoomRef;
+use super::config::ServerAddress;
+use super::connection::{self, Connection, ConnectionError};
+use super::history::HistoryStore;
+use super::profiles::Account;
+use super::protocol::{ClientFrame, ServerFrame};
+use super::security::{self, IdentityKeyPair, RoomKey};
+
+enum ConnectionState {
+    Disconnected,
+    Connecting { join_when_connected: bool },
+    Connected(Connection),
+    Joining(Connection),
+}
 
 pub struct WelcomeViewState {
     welcome_message: String,
+    server_text: String,
     room_id_text: String,
     name_text: String,
-    tcp_stream: TcpStream,
+    passphrase_text: String,
+    server_address: ServerAddress,
+    // rooms from the picked profile, offered as shortcuts
+    default_rooms: Vec<String>,
+    identity: IdentityKeyPair,
+    history: Option<HistoryStore>,
+    // rooms already joined; submitting one of them goes back to it instead
+    open_rooms: Vec<RoomRef>,
+    connection_state: ConnectionState,
+    task_handle: Option<Handle>,
 }
 
 impl WelcomeViewState {
-    pub fn new(tcp_stream: TcpStream) -> Self {
+    pub fn new(account: Account, open_rooms: Vec<RoomRef>) -> (Self, Task<WelcomeViewMessage>) {
+        let server_address = account.server_address;
         let mut welcome_view_state = WelcomeViewState {
-            welcome_message: String::from("Connecting.."),
-            room_id_text: String::new(),
-            name_text: String::new(),
-            tcp_stream,
+            welcome_message: String::new(),
+            server_text: server_address.to_string(),
+            room_id_text: account.rooms.first().cloned().unwrap_or_default(),
+            name_text: account.name,
+            passphrase_text: String::new(),
+            server_address: server_address.clone(),
+            default_rooms: account.rooms,
+            identity: account.identity,
+            history: account.history,
+            open_rooms,
+            connection_state: ConnectionState::Disconnected,
+            task_handle: None,
         };
+        let task = welcome_view_state.connect(server_address, false);
+        (welcome_view_state, task)
+    }
 
-        let mut buf = [0u8; 1024];
-        let bytes_read = welcome_view_state.tcp_stream.read(&mut buf).unwrap();
-        let message = std::str::from_utf8(&buf[..bytes_read]).unwrap();
-        welcome_view_state.welcome_message = message.to_string();
+    fn is_busy(&self) -> bool {
+        matches!(
+            self.connection_state,
+            ConnectionState::Connecting { .. } | ConnectionState::Joining(_)
+        )
+    }
 
-        welcome_view_state
+    fn connect(
+        &mut self,
+        server_address: ServerAddress,
+        join_when_connected: bool,
+    ) -> Task<WelcomeViewMessage> {
+        self.abort_task();
+        self.welcome_message = format!("Connecting to {}…", server_address);
+        self.server_address = server_address.clone();
+        self.connection_state = ConnectionState::Connecting {
+            join_when_connected,
+        };
+        self.track(Task::perform(
+            connection::connect(server_address),
+            WelcomeViewMessage::Connected,
+        ))
+    }
+
+    fn join(&mut self, connection: Connection) -> Task<WelcomeViewMessage> {
+        let join_frame = match ClientFrame::join_room(&self.room_id_text, &self.name_text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.welcome_message = format!("Cannot join: {}", e);
+                self.connection_state = ConnectionState::Connected(connection);
+                return Task::none();
+            }
+        };
+        self.welcome_message = String::from("Joining…");
+        self.connection_state = ConnectionState::Joining(connection.clone());
+        let passphrase = self.passphrase_text.clone();
+        let room_id = self.room_id_text.trim().to_string();
+        self.track(Task::perform(
+            async move {
+                let room_key = tokio::task::spawn_blocking(move || {
+                    security::derive_room_key(&passphrase, &room_id)
+                });
+                let reply = connection::join_room(connection, join_frame).await?;
+                let room_key = room_key
+                    .await
+                    .map_err(|e| ConnectionError::Io(e.to_string()))?;
+                Ok((reply, room_key))
+            },
+            WelcomeViewMessage::JoinReplied,
+        ))
+    }
+
+    fn track(&mut self, task: Task<WelcomeViewMessage>) -> Task<WelcomeViewMessage> {
+        let (task, handle) = task.abortable();
+        self.task_handle = Some(handle);
+        task
+    }
+
+    fn abort_task(&mut self) {
+        if let Some(handle) = self.task_handle.take() {
+            handle.abort();
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum WelcomeViewMessage {
+    ServerChanged(String),
+    ConnectServer,
     NameChanged(String),
     RoomIdChanged(String),
+    PassphraseChanged(String),
     SbmitForm,
+    Connected(Result<(Connection, ServerFrame), ConnectionError>),
+    JoinReplied(Result<(ServerFrame, RoomKey), ConnectionError>),
+    Cancel,
+    ChangeProfile,
 }
 
-#[derive(Debug, Clone)]
 pub enum WelcomeViewAction {
-    // success_message, name, room_id
-    RoomJoined(String, String, String),
+    // success_message, account, room_id, room_key, connection
+    RoomJoined(String, Box<Account>, String, RoomKey, Connection),
+    // the room asked for is already open
+    SelectRoom(RoomRef),
+    Run(Task<WelcomeViewMessage>),
+    ChangeProfile,
     None,
 }
 
@@ -49,6 +150,21 @@ pub fn welcome_view_update(
     message: WelcomeViewMessage,
 ) -> WelcomeViewAction {
     match message {
+        WelcomeViewMessage::ServerChanged(s) => {
+            welcome_view_state.server_text = s;
+            WelcomeViewAction::None
+        }
+        WelcomeViewMessage::ConnectServer => {
+            if welcome_view_state.is_busy() {
+                return WelcomeViewAction::None;
+            }
+            match parse_server_text(welcome_view_state) {
+                Some(server_address) => {
+                    WelcomeViewAction::Run(welcome_view_state.connect(server_address, false))
+                }
+                None => WelcomeViewAction::None,
+            }
+        }
         WelcomeViewMessage::NameChanged(s) => {
             welcome_view_state.name_text = s;
             WelcomeViewAction::None
@@ -57,35 +173,142 @@ pub fn welcome_view_update(
             welcome_view_state.room_id_text = s;
             WelcomeViewAction::None
         }
+        WelcomeViewMessage::PassphraseChanged(s) => {
+            welcome_view_state.passphrase_text = s;
+            WelcomeViewAction::None
+        }
         WelcomeViewMessage::SbmitForm => {
-            let message = format!(
-                "JOIN_ROOM {} {}",
-                welcome_view_state.room_id_text, welcome_view_state.name_text
+            if welcome_view_state.is_busy() {
+                return WelcomeViewAction::None;
+            }
+            let Some(server_address) = parse_server_text(welcome_view_state) else {
+                return WelcomeViewAction::None;
+            };
+            // joining again would take the room over from the session already in it
+            let room = RoomRef {
+                server: server_address.to_string(),
+                room_id: welcome_view_state.room_id_text.trim().to_string(),
+                name: welcome_view_state.name_text.trim().to_string(),
+            };
+            if welcome_view_state.open_rooms.contains(&room) {
+                welcome_view_state.abort_task();
+                return WelcomeViewAction::SelectRoom(room);
+            }
+            match &welcome_view_state.connection_state {
+                ConnectionState::Connected(connection)
+                    if server_address == welcome_view_state.server_address =>
+                {
+                    let connection = connection.clone();
+                    WelcomeViewAction::Run(welcome_view_state.join(connection))
+                }
+                _ => WelcomeViewAction::Run(welcome_view_state.connect(server_address, true)),
+            }
+        }
+        WelcomeViewMessage::Connected(result) => {
+            welcome_view_state.task_handle = None;
+            let join_when_connected = matches!(
+                welcome_view_state.connection_state,
+                ConnectionState::Connecting {
+                    join_when_connected: true
+                }
             );
-            welcome_view_state
-                .tcp_stream
-                .write_all(message.as_bytes())
-                .unwrap();
-
-            let mut buf = [0u8; 1024];
-            let bytes_read = welcome_view_state.tcp_stream.read(&mut buf).unwrap();
-            let message = std::str::from_utf8(&buf[..bytes_read]).unwrap();
-            if message.contains("Room ID") {
-                return WelcomeViewAction::RoomJoined(
-                    format!("ROOM_JOIN_SUCCESS_MESSAGE {}", message.to_string()),
-                    welcome_view_state.name_text.to_string(),
-                    welcome_view_state.room_id_text.to_string(),
-                );
+            match result {
+                Ok((connection, greeting)) => {
+                    welcome_view_state.welcome_message = match greeting {
+                        ServerFrame::Welcome(text) => text,
+                        other => format!("Unexpected greeting from server: {:?}", other),
+                    };
+                    if join_when_connected {
+                        return WelcomeViewAction::Run(welcome_view_state.join(connection));
+                    }
+                    welcome_view_state.connection_state = ConnectionState::Connected(connection);
+                }
+                Err(e) => {
+                    welcome_view_state.connection_state = ConnectionState::Disconnected;
+                    welcome_view_state.welcome_message = format!(
+                        "Could not connect to {}: {}",
+                        welcome_view_state.server_address, e
+                    );
+                }
             }
-            welcome_view_state.welcome_message.push('\n');
-            welcome_view_state.welcome_message.push_str(message);
             WelcomeViewAction::None
         }
+        WelcomeViewMessage::JoinReplied(result) => {
+            welcome_view_state.task_handle = None;
+            let connection = match std::mem::replace(
+                &mut welcome_view_state.connection_state,
+                ConnectionState::Disconnected,
+            ) {
+                ConnectionState::Joining(connection) => connection,
+                other => {
+                    welcome_view_state.connection_state = other;
+                    return WelcomeViewAction::None;
+                }
+            };
+            match result {
+                Ok((ServerFrame::RoomJoinSuccess(message), room_key)) => {
+                    let account = Account {
+                        server_address: welcome_view_state.server_address.clone(),
+                        name: welcome_view_state.name_text.trim().to_string(),
+                        rooms: welcome_view_state.default_rooms.clone(),
+                        identity: welcome_view_state.identity.clone(),
+                        history: welcome_view_state.history.clone(),
+                    };
+                    WelcomeViewAction::RoomJoined(
+                        message,
+                        Box::new(account),
+                        welcome_view_state.room_id_text.trim().to_string(),
+                        room_key,
+                        connection,
+                    )
+                }
+                Ok((ServerFrame::RoomJoinFailure(message), _)) => {
+                    welcome_view_state.connection_state = ConnectionState::Connected(connection);
+                    welcome_view_state.welcome_message = message;
+                    WelcomeViewAction::None
+                }
+                Ok((other, _)) => {
+                    welcome_view_state.connection_state = ConnectionState::Connected(connection);
+                    welcome_view_state.welcome_message =
+                        format!("Unexpected reply to join: {:?}", other);
+                    WelcomeViewAction::None
+                }
+                Err(e) => {
+                    welcome_view_state.welcome_message = format!("Could not join room: {}", e);
+                    WelcomeViewAction::None
+                }
+            }
+        }
+        WelcomeViewMessage::Cancel => {
+            welcome_view_state.abort_task();
+            welcome_view_state.welcome_message = match welcome_view_state.connection_state {
+                ConnectionState::Connecting { .. } => String::from("Connection cancelled"),
+                ConnectionState::Joining(_) => String::from("Join cancelled"),
+                _ => return WelcomeViewAction::None,
+            };
+            // a join reply could still arrive later, so the half-used connection is dropped
+            welcome_view_state.connection_state = ConnectionState::Disconnected;
+            WelcomeViewAction::None
+        }
+        WelcomeViewMessage::ChangeProfile => {
+            welcome_view_state.abort_task();
+            WelcomeViewAction::ChangeProfile
+        }
     }
 }
 
+fn parse_server_text(welcome_view_state: &mut WelcomeViewState) -> Option<ServerAddress> {
+    let server_address = ServerAddress::parse(&welcome_view_state.server_text);
+    if server_address.is_none() {
+        welcome_view_state.welcome_message = format!(
+            "\"{}\" is not a valid server address, expected host:port",
+            welcome_view_state.server_text
+        );
+    }
+    server_address
+}
 
-pub fn welcome_view(welcome_view_state: &WelcomeViewState) -> Element<WelcomeViewMessage> {
+pub fn welcome_view(welcome_view_state: &WelcomeViewState) -> Element<'_, WelcomeViewMessage> {
     let mut title_font = Font::with_name("clash-grotesk-variable");
     title_font.weight = font::Weight::Semibold;
     let title: Element<WelcomeViewMessage> = text("LetsChat!")
@@ -99,28 +322,127 @@ pub fn welcome_view(welcome_view_state: &WelcomeViewState) -> Element<WelcomeVie
         .align_x(alignment::Horizontal::Center)
         .into();
 
-    let name_ip: Element<WelcomeViewMessage> = text_input("What is your name?", &welcome_view_state.name_text)
-        .padding(10)
-        .size(16)
-        .on_input(WelcomeViewMessage::NameChanged)
-        .into();
+    let server_ip: Element<WelcomeViewMessage> = text_input(
+        "Server address (host:port)",
+        &welcome_view_state.server_text,
+    )
+    .padding(10)
+    .size(16)
+    .on_input(WelcomeViewMessage::ServerChanged)
+    .on_submit(WelcomeViewMessage::ConnectServer)
+    .into();
 
-    let room_id_ip: Element<WelcomeViewMessage> = text_input("Which room do you want to join?", &welcome_view_state.room_id_text)
-        .padding(10)
-        .size(16)
-        .on_input(WelcomeViewMessage::RoomIdChanged)
-        .into();
+    let name_ip: Element<WelcomeViewMessage> =
+        text_input("What is your name?", &welcome_view_state.name_text)
+            .padding(10)
+            .size(16)
+            .on_input(WelcomeViewMessage::NameChanged)
+            .into();
+
+    let room_id_ip: Element<WelcomeViewMessage> = text_input(
+        "Which room do you want to join?",
+        &welcome_view_state.room_id_text,
+    )
+    .padding(10)
+    .size(16)
+    .on_input(WelcomeViewMessage::RoomIdChanged)
+    .into();
 
-    let connect_btn: Element<WelcomeViewMessage> = button("Connect")
-        .padding(12)
-        .on_press(WelcomeViewMessage::SbmitForm)
+    let passphrase_ip: Element<WelcomeViewMessage> =
+        text_input("Room passphrase", &welcome_view_state.passphrase_text)
+            .padding(10)
+            .size(16)
+            .secure(true)
+            .on_input(WelcomeViewMessage::PassphraseChanged)
+            .on_submit(WelcomeViewMessage::SbmitForm)
+            .into();
+
+    let connect_btn: Element<WelcomeViewMessage> = if welcome_view_state.is_busy() {
+        let cancel_btn: Element<WelcomeViewMessage> = button("Cancel")
+            .padding(12)
+            .on_press(WelcomeViewMessage::Cancel)
+            .into();
+        row![button("Connect").padding(12), cancel_btn]
+            .spacing(10)
+            .into()
+    } else {
+        button("Connect")
+            .padding(12)
+            .on_press(WelcomeViewMessage::SbmitForm)
+            .into()
+    };
+
+    let default_rooms: Element<WelcomeViewMessage> = Row::from_vec(
+        welcome_view_state
+            .default_rooms
+            .iter()
+            .map(|room| {
+                button(text(room))
+                    .style(button::secondary)
+                    .on_press(WelcomeViewMessage::RoomIdChanged(room.clone()))
+                    .into()
+            })
+            .collect(),
+    )
+    .spacing(10)
+    .into();
+
+    let change_profile_btn: Element<WelcomeViewMessage> = button("Change profile")
+        .style(button::text)
+        .on_press(WelcomeViewMessage::ChangeProfile)
         .into();
 
-    let content: Element<WelcomeViewMessage> = column![title, welcome_text, name_ip, room_id_ip, connect_btn]
-        .spacing(15)
-        .align_x(iced::Alignment::Center).into();
+    let content: Element<WelcomeViewMessage> = column![
+        title,
+        welcome_text,
+        server_ip,
+        name_ip,
+        room_id_ip,
+        default_rooms,
+        passphrase_ip,
+        connect_btn,
+        change_profile_btn
+    ]
+    .spacing(15)
+    .align_x(iced::Alignment::Center)
+    .into();
+
+    container(content).padding(40).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    container(content)
-        .padding(40)
-        .into()
+    #[test]
+    fn submitting_an_open_room_selects_it() {
+        let server_address = ServerAddress::parse("localhost:8000").unwrap();
+        let open = RoomRef {
+            server: server_address.to_string(),
+            room_id: String::from("lobby"),
+            name: String::from("alice"),
+        };
+        let account = Account {
+            server_address,
+            name: String::from("alice"),
+            rooms: vec![String::from("lobby")],
+            identity: IdentityKeyPair::generate(),
+            history: None,
+        };
+        let (mut welcome_view_state, _) = WelcomeViewState::new(account, vec![open.clone()]);
+        // not waiting on the server, which this test never reaches
+        welcome_view_state.connection_state = ConnectionState::Disconnected;
+
+        welcome_view_state.room_id_text = String::from(" lobby ");
+        match welcome_view_update(&mut welcome_view_state, WelcomeViewMessage::SbmitForm) {
+            WelcomeViewAction::SelectRoom(room) => assert_eq!(room, open),
+            _ => panic!("an open room was joined again"),
+        }
+
+        welcome_view_state.room_id_text = String::from("games");
+        assert!(matches!(
+            welcome_view_update(&mut welcome_view_state, WelcomeViewMessage::SbmitForm),
+            WelcomeViewAction::Run(_)
+        ));
+    }
 }
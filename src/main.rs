@@ -1,23 +1,20 @@
 mod app;
 
-use std::{net::TcpStream, process::exit};
+use std::process::exit;
 
-use iced::{Font, Task, Theme};
+use iced::Font;
 
 #[tokio::main]
 async fn main() {
-    let tcp_stream = TcpStream::connect("localhost:8000").unwrap();
-    let app_state = app::AppState::new(tcp_stream.try_clone().unwrap());
+    let config = app::config::Config::load();
     iced::application("LetsChat", app::update, app::view)
-        .theme(|_m| Theme::KanagawaLotus)
+        .theme(|_m| app::THEME)
         .font(include_bytes!("./fonts/font.ttf"))
         .default_font(Font::DEFAULT)
         .subscription(app::subscription)
-        .run_with(|| (app_state, Task::none()))
+        // closing goes through app::update so an open room is left cleanly
+        .exit_on_close_request(false)
+        .run_with(move || app::AppState::new(config))
         .unwrap();
-    match tcp_stream.shutdown(std::net::Shutdown::Both) {
-        Ok(_) => {}
-        Err(_e) => {}
-    };
     exit(0);
 }